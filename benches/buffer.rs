@@ -0,0 +1,131 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use oxidate::buffer::Buffer;
+
+const LINES: usize = 200_000;
+const VIEWPORT: usize = 50;
+
+/// The `Vec<String>` store `Buffer` used before the rope, kept here as a
+/// baseline.
+struct VecBuffer {
+    lines: Vec<String>,
+}
+
+impl VecBuffer {
+    fn from_text(text: &str) -> Self {
+        Self { lines: text.lines().map(|s| s.to_string()).collect() }
+    }
+
+    fn get(&self, line: usize) -> Option<String> {
+        self.lines.get(line).cloned()
+    }
+
+    fn insert(&mut self, x: usize, y: usize, c: char) {
+        if let Some(line) = self.lines.get_mut(y) {
+            line.insert(x, c);
+        }
+    }
+
+    fn insert_line(&mut self, line: usize, text: &str) {
+        self.lines.insert(line, text.to_string());
+    }
+
+    fn remove_line(&mut self, line: usize) {
+        self.lines.remove(line);
+    }
+}
+
+fn sample_text() -> String {
+    (0..LINES)
+        .map(|i| format!("{i:>8}: the quick brown fox jumps over the lazy dog"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn bench_load(c: &mut Criterion) {
+    let text = sample_text();
+    let mut group = c.benchmark_group("load");
+    group.bench_function("rope", |b| b.iter(|| Buffer::from_text(black_box(&text))));
+    group.bench_function("vec", |b| b.iter(|| VecBuffer::from_text(black_box(&text))));
+    group.finish();
+}
+
+fn bench_render(c: &mut Criterion) {
+    let text = sample_text();
+    let rope = Buffer::from_text(&text);
+    let vec = VecBuffer::from_text(&text);
+    let top = LINES / 2;
+
+    let mut group = c.benchmark_group("render_viewport");
+    group.bench_function("rope", |b| {
+        b.iter(|| {
+            let mut width = 0;
+            for line in rope.lines_at(top).take(VIEWPORT) {
+                for chunk in line.chunks() {
+                    width += black_box(chunk).len();
+                }
+            }
+            width
+        })
+    });
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            let mut width = 0;
+            for i in top..top + VIEWPORT {
+                width += black_box(vec.get(i).unwrap()).len();
+            }
+            width
+        })
+    });
+    group.finish();
+}
+
+fn bench_edit(c: &mut Criterion) {
+    let text = sample_text();
+    let rope = Buffer::from_text(&text);
+    let vec = VecBuffer::from_text(&text);
+
+    let mut group = c.benchmark_group("insert_line_top");
+    group.bench_function("rope", |b| {
+        b.iter_batched_ref(
+            || Buffer::from_text(&text),
+            |buf| buf.insert_line(0, "inserted"),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("vec", |b| {
+        b.iter_batched_ref(
+            || VecBuffer::from_text(&text),
+            |buf| buf.insert_line(0, "inserted"),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+
+    // Repeated edits on one buffer, closer to how typing behaves.
+    let mut group = c.benchmark_group("insert_remove_line_top");
+    let mut buf = rope;
+    group.bench_function("rope", |b| {
+        b.iter(|| {
+            buf.insert_line(0, "inserted");
+            buf.remove_line(0);
+        })
+    });
+    let mut buf = vec;
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            buf.insert_line(0, "inserted");
+            buf.remove_line(0);
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("insert_char_middle");
+    let mut buf = Buffer::from_text(&text);
+    group.bench_function("rope", |b| b.iter(|| buf.insert(4, LINES / 2, 'x')));
+    let mut buf = VecBuffer::from_text(&text);
+    group.bench_function("vec", |b| b.iter(|| buf.insert(4, LINES / 2, 'x')));
+    group.finish();
+}
+
+criterion_group!(benches, bench_load, bench_render, bench_edit);
+criterion_main!(benches);
@@ -1,68 +1,921 @@
+use ropey::{Rope, RopeSlice};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::filetype;
+use crate::grapheme;
+use crate::history::{self, Change, History};
+use crate::operator::Range;
+use crate::options::{Settings, StrValue, Value};
+use crate::save;
+use crate::swap::{self, Snapshot};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+
+    /// The `fileformat` name.
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+        }
+    }
+}
+
+impl StrValue for LineEnding {
+    fn get(&self) -> String {
+        self.name().to_string()
+    }
+
+    fn set(&mut self, value: &str) -> bool {
+        *self = match value {
+            "unix" => LineEnding::Unix,
+            "dos" => LineEnding::Dos,
+            _ => return false,
+        };
+        true
+    }
+}
+
+/// Files are always read and written as UTF-8; this is what the
+/// `fileencoding` option reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+}
+
+impl StrValue for Encoding {
+    fn get(&self) -> String {
+        "utf-8".to_string()
+    }
+
+    fn set(&mut self, value: &str) -> bool {
+        matches!(value, "utf-8" | "utf8")
+    }
+}
+
 pub struct Buffer {
     pub file: Option<String>,
-    pub lines: Vec<String>,
+    /// The file didn't exist when it was opened; it is created on save.
+    pub new_file: bool,
+    pub readonly: bool,
+    /// The file couldn't be read, so it is never written over, even with
+    /// `!`.
+    pub unreadable: bool,
+    pub line_ending: LineEnding,
+    /// Whether the last line ends with a line break.
+    pub final_newline: bool,
+    /// Whether the file starts with a UTF-8 byte order mark.
+    pub bom: bool,
+    /// Whether the file had no lines at all, as opposed to one empty line.
+    /// It is written back that way for as long as the buffer stays empty.
+    no_lines: bool,
+    pub encoding: Encoding,
+    /// Language of the file, e.g. `rust`; empty if unknown.
+    pub filetype: String,
+    text: Rope,
+    history: History,
+    /// Counts changes to the text, including undo and redo.
+    tick: usize,
+    saved: SavedState,
+    /// `tick` when the swap file was last brought up to date.
+    swap_tick: usize,
+    swap: Option<PathBuf>,
+    /// Whether `swap` is ours to remove.
+    swap_written: bool,
+    /// Positions set with `m`, and `<` and `>` for the last Visual
+    /// selection. They move with the lines they are on.
+    marks: HashMap<char, (usize, usize)>,
+    /// Lines `:global` has yet to visit, in order. Like marks they move
+    /// with their lines, but a line joined into another is dropped.
+    marked_lines: VecDeque<usize>,
+}
+
+/// What the buffer looked like when it was last loaded or written, to tell
+/// whether it has been modified since.
+#[derive(PartialEq)]
+struct SavedState {
+    revision: usize,
+    line_ending: LineEnding,
+    final_newline: bool,
+    bom: bool,
+}
+
+/// Why a file couldn't be loaded into a buffer.
+#[derive(Debug)]
+pub enum OpenError {
+    PermissionDenied(String),
+    InvalidUtf8(String),
+    IsDirectory(String),
+    Io(String, io::Error),
 }
 
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenError::PermissionDenied(file) => write!(f, "\"{file}\" [Permission Denied]"),
+            OpenError::InvalidUtf8(file) => write!(f, "\"{file}\" [Not valid UTF-8]"),
+            OpenError::IsDirectory(file) => write!(f, "\"{file}\" is a directory"),
+            OpenError::Io(file, e) => write!(f, "\"{file}\" {e}"),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
 impl Buffer {
-    pub fn from_file(file: Option<String>) -> Self {
-        let lines = match &file {
-            Some(file) => std::fs::read_to_string(file)
-                .unwrap()
-                .lines()
-                .map(|s| s.to_string())
-                .collect(),
-            None => vec![String::new()],
+    /// An empty buffer for `file`.
+    pub fn new(file: Option<String>) -> Self {
+        Self {
+            swap: file.as_deref().and_then(swap::swap_file),
+            filetype: file.as_deref().map_or("", |f| filetype::detect(f, "")).to_string(),
+            file,
+            new_file: false,
+            readonly: false,
+            unreadable: false,
+            line_ending: LineEnding::Unix,
+            final_newline: true,
+            bom: false,
+            no_lines: true,
+            encoding: Encoding::Utf8,
+            text: Rope::new(),
+            history: History::new(),
+            tick: 0,
+            saved: SavedState { revision: 0, line_ending: LineEnding::Unix, final_newline: true, bom: false },
+            swap_tick: 0,
+            swap_written: false,
+            marks: HashMap::new(),
+            marked_lines: VecDeque::new(),
+        }
+    }
+
+    /// Loads `file`. A file that doesn't exist yet opens as an empty buffer
+    /// that creates it on save.
+    pub fn from_file(file: Option<String>) -> Result<Self, OpenError> {
+        let Some(path) = file else { return Ok(Self::new(None)) };
+
+        let contents = match std::fs::read(&path) {
+            Ok(bytes) => String::from_utf8(bytes).map_err(|_| OpenError::InvalidUtf8(path.clone()))?,
+            Err(e) => {
+                return match e.kind() {
+                    io::ErrorKind::NotFound => Ok(Self { new_file: true, ..Self::new(Some(path)) }),
+                    io::ErrorKind::PermissionDenied => Err(OpenError::PermissionDenied(path)),
+                    io::ErrorKind::IsADirectory => Err(OpenError::IsDirectory(path)),
+                    _ => Err(OpenError::Io(path, e)),
+                };
+            }
+        };
+
+        let (contents, bom) = match contents.strip_prefix('\u{feff}') {
+            Some(rest) => (rest, true),
+            None => (contents.as_str(), false),
+        };
+
+        // Only a file where every line ends in CRLF is treated as DOS; any
+        // other `\r` is kept as part of the text.
+        let (mut lf, mut crlf) = (0, 0);
+        for (i, b) in contents.bytes().enumerate() {
+            if b == b'\n' {
+                lf += 1;
+                crlf += (i > 0 && contents.as_bytes()[i - 1] == b'\r') as usize;
+            }
+        }
+        let line_ending = if lf > 0 && lf == crlf { LineEnding::Dos } else { LineEnding::Unix };
+        let contents = match line_ending {
+            LineEnding::Dos => std::borrow::Cow::Owned(contents.replace("\r\n", "\n")),
+            LineEnding::Unix => std::borrow::Cow::Borrowed(contents),
         };
 
-        Self { file, lines }
+        let filetype = filetype::detect(&path, contents.lines().next().unwrap_or_default()).to_string();
+        let final_newline = contents.is_empty() || contents.ends_with('\n');
+        let text = Rope::from_str(contents.strip_suffix('\n').unwrap_or(&contents));
+        let file = Some(path);
+
+        // An undo file that is unreadable or was written for other contents
+        // is ignored; it gets replaced on the next save.
+        let history = file
+            .as_deref()
+            .and_then(history::undo_file)
+            .and_then(|path| History::load(&path, history::content_hash(&text), &text).ok())
+            .flatten()
+            .unwrap_or_default();
+
+        let no_lines = contents.is_empty();
+        let mut buffer = Self { line_ending, final_newline, bom, no_lines, filetype, text, history, ..Self::new(file) };
+        buffer.saved = buffer.state();
+        Ok(buffer)
+    }
+
+    pub fn from_text(text: &str) -> Self {
+        Self { text: Rope::from_str(text), ..Self::new(None) }
     }
 
-    pub fn get(&self, line: usize) -> Option<String> {
-        if self.lines.len() > line {
-            return Some(self.lines[line].clone());
+    /// Borrows a line without its trailing line break.
+    pub fn get(&self, line: usize) -> Option<RopeSlice<'_>> {
+        if self.len() > line {
+            return self.lines_at(line).next();
         }
 
         None
     }
 
+    /// Iterates over lines starting at `line`, without their line breaks.
+    /// Cheaper than repeated `get` calls when walking consecutive lines.
+    pub fn lines_at(&self, line: usize) -> impl Iterator<Item = RopeSlice<'_>> {
+        let start = line.min(self.len());
+        self.text.lines_at(start).map(|slice| {
+            let len = slice.len_chars();
+            if len > 0 && slice.char(len - 1) == '\n' {
+                slice.slice(..len - 1)
+            } else {
+                slice
+            }
+        })
+    }
+
     pub fn len(&self) -> usize {
-        self.lines.len()
+        self.text.len_lines()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.len_chars() == 0
+    }
+
+    /// Length of a line in chars, excluding the line break.
+    pub fn line_len(&self, line: usize) -> usize {
+        self.get(line).map(|l| l.len_chars()).unwrap_or(0)
+    }
+
+    /// Column of the first character of line `y` that isn't a space or
+    /// tab, or of the last character if they all are.
+    pub fn first_non_blank(&self, y: usize) -> usize {
+        let Some(line) = self.get(y) else { return 0 };
+        let blanks = line.chars().take_while(|c| matches!(c, ' ' | '\t')).count();
+        if blanks == line.len_chars() {
+            return grapheme::prev_boundary(line, blanks);
+        }
+        blanks
+    }
+
+    /// Column of the grapheme boundary after column `x`.
+    pub fn next_grapheme(&self, x: usize, y: usize) -> usize {
+        self.get(y).map(|line| grapheme::next_boundary(line, x)).unwrap_or(0)
+    }
+
+    /// Column of the grapheme boundary before column `x`.
+    pub fn prev_grapheme(&self, x: usize, y: usize) -> usize {
+        self.get(y).map(|line| grapheme::prev_boundary(line, x)).unwrap_or(0)
+    }
+
+    /// Number of screen columns taken up by line `y` before column `x`.
+    pub fn display_col(&self, x: usize, y: usize, tabstop: usize) -> usize {
+        let Some(line) = self.get(y) else { return 0 };
+        let line = line.slice(..x.min(line.len_chars()));
+        grapheme::graphemes(line).fold(0, |col, g| col + grapheme::width(g, col, tabstop))
+    }
+
+    /// Column of the grapheme covering screen column `col` of line `y`, or
+    /// the end of the line if it is shorter than that.
+    pub fn col_to_char(&self, col: usize, y: usize, tabstop: usize) -> usize {
+        let Some(line) = self.get(y) else { return 0 };
+        let (mut x, mut width) = (0, 0);
+        for g in grapheme::graphemes(line) {
+            width += grapheme::width(g, width, tabstop);
+            if width > col {
+                break;
+            }
+            x += g.len_chars();
+        }
+        x
+    }
+
+    /// The whole text, lines joined by `\n`.
+    pub fn text(&self) -> RopeSlice<'_> {
+        self.text.slice(..)
+    }
+
+    /// Converts a `(column, line)` position into a char index.
+    pub fn char_idx(&self, x: usize, y: usize) -> usize {
+        self.text.line_to_char(y) + x.min(self.line_len(y))
+    }
+
+    /// Converts a char index into a `(column, line)` position.
+    pub fn char_to_pos(&self, idx: usize) -> (usize, usize) {
+        let idx = idx.min(self.text.len_chars());
+        let line = self.text.char_to_line(idx);
+        (idx - self.text.line_to_char(line), line)
+    }
+
+    /// Replaces the chars in `start..end` with `text`, recording the change
+    /// in the undo history. Every mutation of the buffer goes through here.
+    fn edit(&mut self, start: usize, end: usize, text: &str) {
+        let removed = self.text.slice(start..end).to_string();
+        self.adjust_marks(start, &removed, text);
+        self.text.remove(start..end);
+        self.text.insert(start, text);
+        self.history.record(Change { pos: start, removed, inserted: text.to_string() });
+        self.tick += 1;
+    }
+
+    /// Moves marks along with their lines for an edit at char `start` that
+    /// replaces `removed` with `inserted`. Marks on lines deleted whole go
+    /// away; those on lines joined into another go with them.
+    fn adjust_marks(&mut self, start: usize, removed: &str, inserted: &str) {
+        if self.marks.is_empty() && self.marked_lines.is_empty() {
+            return;
+        }
+        let (x, y) = self.char_to_pos(start);
+        let removed_lines = removed.matches('\n').count();
+        let added = inserted.matches('\n').count() as isize - removed_lines as isize;
+        let whole = |text: &str| text.is_empty() || text.ends_with('\n');
+        // Whole lines replaced by whole lines, or the last lines of the
+        // buffer deleted along with the line break before them.
+        let (deleted, shifted) = if x == 0 && whole(removed) && whole(inserted) {
+            (y..y + removed_lines, y + removed_lines)
+        } else if removed.starts_with('\n') && start + removed.chars().count() == self.text.len_chars() {
+            (y + 1..y + 1 + removed_lines, usize::MAX)
+        } else {
+            (0..0, y + removed_lines + 1)
+        };
+        let last_inserted = y + inserted.matches('\n').count();
+        // Where a line ends up, and whether it was joined into another.
+        let moved = |line: usize| {
+            if deleted.contains(&line) {
+                None
+            } else if line >= shifted {
+                Some((line.saturating_add_signed(added), false))
+            } else if line > y && deleted.is_empty() {
+                Some((line.min(last_inserted), line > last_inserted))
+            } else {
+                Some((line, false))
+            }
+        };
+
+        self.marks.retain(|_, (_, line)| match moved(*line) {
+            Some((to, _)) => {
+                *line = to;
+                true
+            }
+            None => false,
+        });
+        self.marked_lines.retain_mut(|line| match moved(*line) {
+            Some((to, false)) => {
+                *line = to;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    pub fn set_mark(&mut self, name: char, pos: (usize, usize)) {
+        self.marks.insert(name, pos);
+    }
+
+    pub fn mark(&self, name: char) -> Option<(usize, usize)> {
+        self.marks.get(&name).copied()
+    }
+
+    /// Marks `lines`, in order, for `:global` to visit.
+    pub fn mark_lines(&mut self, lines: impl IntoIterator<Item = usize>) {
+        self.marked_lines = lines.into_iter().collect();
+    }
+
+    /// Takes the first marked line that is still there, where it is now.
+    pub fn next_marked_line(&mut self) -> Option<usize> {
+        self.marked_lines.pop_front()
+    }
+
+    pub fn clear_marked_lines(&mut self) {
+        self.marked_lines.clear();
+    }
+
+    pub fn insert(&mut self, x: usize, y: usize, c: char) {
+        if y < self.len() {
+            let idx = self.char_idx(x, y);
+            self.edit(idx, idx, c.encode_utf8(&mut [0; 4]));
+        }
+    }
+
+    pub fn insert_str(&mut self, x: usize, y: usize, text: &str) {
+        if y < self.len() {
+            let idx = self.char_idx(x, y);
+            self.edit(idx, idx, text);
+        }
+    }
+
+    pub fn char_at(&self, x: usize, y: usize) -> Option<char> {
+        self.get(y).filter(|line| x < line.len_chars()).map(|line| line.char(x))
+    }
+
+    /// Deletes the grapheme cluster starting at column `x`.
+    pub fn delete(&mut self, x: usize, y: usize) {
+        if y < self.len() && x < self.line_len(y) {
+            let idx = self.char_idx(x, y);
+            let end = self.char_idx(self.next_grapheme(x, y), y);
+            self.edit(idx, end, "");
+        }
+    }
+
+    /// Inserts `text` as a new line before `line`. `line` may be equal to
+    /// `len()` to append at the end of the buffer.
+    pub fn insert_line(&mut self, line: usize, text: &str) {
+        let len = self.len();
+        if line < len {
+            let idx = self.text.line_to_char(line);
+            self.edit(idx, idx, &format!("{text}\n"));
+        } else if line == len {
+            let idx = self.text.len_chars();
+            self.edit(idx, idx, &format!("\n{text}"));
+        }
+    }
+
+    pub fn remove_line(&mut self, line: usize) {
+        let len = self.len();
+        if line >= len {
+            return;
+        }
+
+        if len == 1 {
+            self.edit(0, self.text.len_chars(), "");
+        } else if line + 1 < len {
+            let start = self.text.line_to_char(line);
+            let end = self.text.line_to_char(line + 1);
+            self.edit(start, end, "");
+        } else {
+            // Last line: take the preceding line break with it.
+            let start = self.text.line_to_char(line) - 1;
+            self.edit(start, self.text.len_chars(), "");
+        }
     }
 
-    pub fn insert(&mut self, x: u16, y: u16, c: char) {
-        if let Some(line) = self.lines.get_mut(y as usize) {
-            (*line).insert(x as usize, c);
+    /// Splits `line` at char column `x`, moving the tail onto a new line.
+    pub fn split_line(&mut self, x: usize, line: usize) {
+        if line < self.len() {
+            let idx = self.char_idx(x, line);
+            self.edit(idx, idx, "\n");
         }
     }
 
-    pub fn delete(&mut self, x: u16, y: u16) {
-        if let Some(line) = self.lines.get_mut(y as usize) {
-            (*line).remove(x as usize);
+    /// Appends the line after `line` onto it, removing the line break.
+    pub fn join_lines(&mut self, line: usize) {
+        if line + 1 < self.len() {
+            let idx = self.text.line_to_char(line + 1) - 1;
+            self.edit(idx, idx + 1, "");
         }
     }
 
-    pub fn remove_line(&mut self, line: u16) {
-        if self.len() > line as usize {
-            self.lines.remove(line as usize);
+    /// Moves `lines` so that the first of them becomes line `to` of what is
+    /// left without them. Their marks go with them rather than being
+    /// dropped along with the lines deleted on the way.
+    pub fn move_lines(&mut self, lines: RangeInclusive<usize>, to: usize) {
+        let (first, last) = lines.into_inner();
+        let count = last - first + 1;
+        let moved = |line: usize| {
+            if (first..=last).contains(&line) {
+                return line - first + to;
+            }
+            let rest = if line > last { line - count } else { line };
+            if rest >= to { rest + count } else { rest }
+        };
+        let marks = std::mem::take(&mut self.marks);
+        let marked_lines = std::mem::take(&mut self.marked_lines);
+
+        let range = Range { start: (0, first), end: (0, last), linewise: true };
+        let text = self.text_in(&range);
+        self.delete_range(&range);
+        self.insert_line(to, &text[..text.len() - 1]);
+
+        self.marks = marks.into_iter().map(|(name, (x, y))| (name, (x, moved(y)))).collect();
+        self.marked_lines = marked_lines.into_iter().map(moved).collect();
+    }
+
+    /// Char indices of the text in `range`. A linewise range stops before
+    /// the line break after its last line.
+    fn span(&self, range: &Range) -> (usize, usize) {
+        if range.linewise {
+            let (first, last) = (range.start.1, range.end.1.min(self.len() - 1));
+            (self.text.line_to_char(first), self.char_idx(self.line_len(last), last))
+        } else {
+            (self.char_idx(range.start.0, range.start.1), self.char_idx(range.end.0, range.end.1))
+        }
+    }
+
+    /// The text in `range`, with a line break after every line if it is
+    /// linewise.
+    pub fn text_in(&self, range: &Range) -> String {
+        let (start, end) = self.span(range);
+        let text = self.text.slice(start..end).to_string();
+        if range.linewise { text + "\n" } else { text }
+    }
+
+    /// Replaces the text in `range`. The lines of a linewise range are
+    /// replaced but not their last line break.
+    pub fn replace_range(&mut self, range: &Range, text: &str) {
+        let (start, end) = self.span(range);
+        if self.text.slice(start..end) != text {
+            self.edit(start, end, text);
+        }
+    }
+
+    /// Deletes the text in `range`. A linewise range takes the line break
+    /// after it along, or the one before it at the end of the buffer.
+    pub fn delete_range(&mut self, range: &Range) {
+        let (mut start, mut end) = self.span(range);
+        if range.linewise {
+            if range.end.1 + 1 < self.len() {
+                end += 1;
+            } else {
+                start = start.saturating_sub(1);
+            }
         }
+        if start < end {
+            self.edit(start, end, "");
+        }
+    }
+
+    /// Ends the current undo step. Edits made since the last commit are
+    /// undone and redone together.
+    pub fn commit(&mut self) {
+        self.history.commit();
+    }
+
+    /// Undoes the last change, returning the position it happened at.
+    pub fn undo(&mut self) -> Option<(usize, usize)> {
+        let idx = self.history.undo(&mut self.text);
+        self.changed_at(idx)
+    }
+
+    pub fn redo(&mut self) -> Option<(usize, usize)> {
+        let idx = self.history.redo(&mut self.text);
+        self.changed_at(idx)
     }
 
-    pub fn save(&self) -> std::io::Result<String> {
+    /// Jumps to the state right after change `n` (`:undo N`).
+    pub fn undo_to(&mut self, n: usize) -> Option<(usize, usize)> {
+        let idx = self.history.jump_to(n, &mut self.text);
+        self.changed_at(idx)
+    }
+
+    /// Moves `count` states back or forward in time (`g-`, `g+`).
+    pub fn undo_step(&mut self, count: isize) -> Option<(usize, usize)> {
+        let idx = self.history.step(count, &mut self.text);
+        self.changed_at(idx)
+    }
+
+    /// Moves to the state `duration` earlier or later (`:earlier 10m`).
+    pub fn undo_travel(&mut self, duration: Duration, forward: bool) -> Option<(usize, usize)> {
+        let idx = self.history.travel(duration, forward, &mut self.text);
+        self.changed_at(idx)
+    }
+
+    /// Position of a change made by moving through the undo history.
+    fn changed_at(&mut self, idx: Option<usize>) -> Option<(usize, usize)> {
+        let idx = idx?;
+        self.tick += 1;
+        Some(self.char_to_pos(idx))
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Writes the buffer to its file, keeping the old contents as `file~`
+    /// if `backup` is set. A readonly buffer is only written with `force`,
+    /// and one whose file couldn't be read not at all.
+    pub fn save(&mut self, backup: bool, force: bool) -> std::io::Result<String> {
+        if let (true, Some(file)) = (self.unreadable, &self.file) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{file:?} could not be read; not overwriting it"),
+            ));
+        }
+        if self.readonly && !force {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "E45: 'readonly' option is set (add ! to override)",
+            ));
+        }
+
         if let Some(file) = &self.file {
-            let contents = self.lines.join("\n");
-            std::fs::write(file, &contents)?;
+            let bytes = save::write(file.as_ref(), backup, |file| {
+                let mut writer = io::BufWriter::new(file);
+                let bytes = self.write_contents(&mut writer)?;
+                writer.flush()?;
+                Ok(bytes)
+            })?;
+            if let Some(path) = history::undo_file(file) {
+                // Losing the undo history shouldn't fail the write itself.
+                let _ = self.history.save(&path, history::content_hash(&self.text));
+            }
             let message = format!(
-                "{:?} {}L, {}B written",
+                "{:?} {}{}{}{}L, {}B written",
                 file,
-                self.lines.len(),
-                contents.as_bytes().len()
+                if self.new_file { "[New] " } else { "" },
+                if self.line_ending == LineEnding::Dos { "[dos] " } else { "" },
+                if self.final_newline { "" } else { "[noeol] " },
+                if self.has_no_lines() { 0 } else { self.len() },
+                bytes
             );
+            self.new_file = false;
+            self.commit();
+            self.saved = self.state();
+            self.remove_swap();
             Ok(message)
         } else {
             Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
-                "No file specified to save the buffer.",
+                "E32: No file name",
             ))
         }
     }
+
+    /// Whether the buffer differs from the file, in its text or in how it
+    /// is written. Undoing back to the saved text clears this.
+    pub fn modified(&self) -> bool {
+        self.history.has_pending() || self.saved != self.state()
+    }
+
+    fn state(&self) -> SavedState {
+        SavedState {
+            revision: self.history.current(),
+            line_ending: self.line_ending,
+            final_newline: self.final_newline,
+            bom: self.bom,
+        }
+    }
+
+    /// Counts changes to the text; it moves on with every edit, undo and
+    /// redo.
+    pub fn tick(&self) -> usize {
+        self.tick
+    }
+
+    /// The whole text, with `\n` line breaks.
+    pub fn contents(&self) -> String {
+        self.text.to_string()
+    }
+
+    pub fn swap_file(&self) -> Option<&std::path::Path> {
+        self.swap.as_deref()
+    }
+
+    /// What should be written to the swap file, or `None` if the buffer has
+    /// no unsaved changes.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        if !self.modified() {
+            return None;
+        }
+        Some(Snapshot { path: self.swap.clone()?, file: self.file.clone()?, text: self.text.clone() })
+    }
+
+    /// Number of changes not in the swap file yet.
+    pub fn changes_since_swap(&self) -> usize {
+        self.tick - self.swap_tick
+    }
+
+    /// Brings the swap file up to date with the unsaved changes.
+    pub fn write_swap(&mut self) -> io::Result<()> {
+        if self.tick == self.swap_tick {
+            return Ok(());
+        }
+        match self.snapshot() {
+            Some(snapshot) => {
+                snapshot.write()?;
+                self.swap_written = true;
+            }
+            None => self.remove_swap(),
+        }
+        self.swap_tick = self.tick;
+        Ok(())
+    }
+
+    /// Removes the swap file, if this buffer wrote it.
+    pub fn remove_swap(&mut self) {
+        if let (Some(path), true) = (&self.swap, self.swap_written) {
+            let _ = std::fs::remove_file(path);
+        }
+        self.swap_written = false;
+        self.swap_tick = self.tick;
+    }
+
+    /// Stops keeping a swap file for this buffer.
+    pub fn detach_swap(&mut self) {
+        self.swap = None;
+        self.swap_written = false;
+    }
+
+    /// Replaces the text with `text` recovered from a swap file. The swap
+    /// file is taken over, so it goes away once the buffer is written.
+    pub fn recover(&mut self, text: &str) {
+        self.edit(0, self.text.len_chars(), text);
+        self.commit();
+        self.swap_written = true;
+    }
+
+    fn has_no_lines(&self) -> bool {
+        self.no_lines && self.text.len_chars() == 0
+    }
+
+    /// Writes the text the way it was read: with its BOM, line endings and
+    /// final line break. Returns the number of bytes written.
+    fn write_contents(&self, writer: &mut impl Write) -> io::Result<usize> {
+        let mut bytes = 0;
+        let mut write = |data: &str| {
+            bytes += data.len();
+            writer.write_all(data.as_bytes())
+        };
+
+        if self.bom {
+            write("\u{feff}")?;
+        }
+        // A file with no lines is written back without any, not as one
+        // empty line.
+        if self.has_no_lines() {
+            return Ok(bytes);
+        }
+        for chunk in self.text.chunks() {
+            for (i, part) in chunk.split('\n').enumerate() {
+                if i > 0 {
+                    write(self.line_ending.as_str())?;
+                }
+                write(part)?;
+            }
+        }
+        if self.final_newline {
+            write(self.line_ending.as_str())?;
+        }
+        Ok(bytes)
+    }
+}
+
+impl Settings for Buffer {
+    fn lookup(&mut self, name: &str) -> Option<(&'static str, Value<'_>)> {
+        Some(match name {
+            "fileformat" | "ff" => ("fileformat", Value::Str(&mut self.line_ending)),
+            "endofline" | "eol" => ("endofline", Value::Bool(&mut self.final_newline)),
+            "bomb" => ("bomb", Value::Bool(&mut self.bom)),
+            "fileencoding" | "fenc" => ("fileencoding", Value::Str(&mut self.encoding)),
+            "filetype" | "ft" => ("filetype", Value::Str(&mut self.filetype)),
+            "readonly" | "ro" => ("readonly", Value::Bool(&mut self.readonly)),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `contents` from a file and writes the buffer back out.
+    fn round_trip(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("oxidate-test-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let buffer = Buffer::from_file(Some(path.to_string_lossy().into_owned())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut written = Vec::new();
+        let bytes = buffer.write_contents(&mut written).unwrap();
+        assert_eq!(bytes, written.len());
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn unreadable_files_are_not_written_over() {
+        let path = std::env::temp_dir().join(format!("oxidate-test-{}-unreadable", std::process::id()));
+        std::fs::write(&path, b"\xff\xfe not utf-8").unwrap();
+        let file = path.to_string_lossy().into_owned();
+        assert!(Buffer::from_file(Some(file.clone())).is_err());
+
+        let mut buffer = Buffer::new(Some(file));
+        buffer.unreadable = true;
+        let result = buffer.save(false, true);
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(contents, b"\xff\xfe not utf-8");
+    }
+
+    #[test]
+    fn empty_files_are_written_back_unchanged() {
+        for (name, contents) in [
+            ("empty", ""),
+            ("lf", "\n"),
+            ("crlf", "\r\n"),
+            ("bom", "\u{feff}"),
+            ("bom-lf", "\u{feff}\n"),
+        ] {
+            assert_eq!(round_trip(name, contents), contents, "{name}");
+        }
+    }
+
+    #[test]
+    fn line_endings_are_written_back_unchanged() {
+        for (name, contents) in [("unix", "a\nb\n"), ("dos", "a\r\nb\r\n"), ("noeol", "a\nb"), ("mixed", "a\r\nb\n")] {
+            assert_eq!(round_trip(name, contents), contents, "{name}");
+        }
+    }
+
+    /// A buffer of the lines `a` to `e`, each with a mark of its own name
+    /// at its end, and all of them marked for `:global`.
+    fn marked() -> Buffer {
+        let mut buffer = Buffer::from_text("a\nb\nc\nd\ne");
+        for (y, name) in ('a'..='e').enumerate() {
+            buffer.set_mark(name, (1, y));
+        }
+        buffer.mark_lines(0..5);
+        buffer
+    }
+
+    /// The line each mark is on, and the lines still marked for `:global`.
+    fn lines(mut buffer: Buffer) -> (Vec<Option<usize>>, Vec<usize>) {
+        let marks = ('a'..='e').map(|name| buffer.mark(name).map(|(x, y)| {
+            assert_eq!(x, 1);
+            y
+        }));
+        (marks.collect(), std::iter::from_fn(|| buffer.next_marked_line()).collect())
+    }
+
+    #[test]
+    fn marks_follow_inserted_lines() {
+        let mut buffer = marked();
+        buffer.insert_line(1, "x");
+        assert_eq!(lines(buffer), (vec![Some(0), Some(2), Some(3), Some(4), Some(5)], vec![0, 2, 3, 4, 5]));
+
+        let mut buffer = marked();
+        buffer.insert_line(5, "x");
+        assert_eq!(lines(buffer), (vec![Some(0), Some(1), Some(2), Some(3), Some(4)], vec![0, 1, 2, 3, 4]));
+
+        let mut buffer = marked();
+        buffer.split_line(1, 0);
+        assert_eq!(lines(buffer), (vec![Some(0), Some(2), Some(3), Some(4), Some(5)], vec![0, 2, 3, 4, 5]));
+
+        // Typing on a line doesn't move any marks.
+        let mut buffer = marked();
+        buffer.insert_str(0, 2, "xy");
+        assert_eq!(lines(buffer).0, [Some(0), Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn marks_on_deleted_lines_go() {
+        let mut buffer = marked();
+        buffer.remove_line(1);
+        assert_eq!(lines(buffer), (vec![Some(0), None, Some(1), Some(2), Some(3)], vec![0, 1, 2, 3]));
+
+        // The last line goes with the line break before it.
+        let mut buffer = marked();
+        buffer.remove_line(4);
+        assert_eq!(lines(buffer), (vec![Some(0), Some(1), Some(2), Some(3), None], vec![0, 1, 2, 3]));
+
+        let mut buffer = marked();
+        buffer.delete_range(&Range { start: (0, 1), end: (0, 2), linewise: true });
+        assert_eq!(lines(buffer), (vec![Some(0), None, None, Some(1), Some(2)], vec![0, 1, 2]));
+
+        let mut buffer = marked();
+        buffer.delete_range(&Range { start: (0, 3), end: (0, 4), linewise: true });
+        assert_eq!(lines(buffer), (vec![Some(0), Some(1), Some(2), None, None], vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn marks_move_with_moved_lines() {
+        let mut buffer = marked();
+        buffer.move_lines(1..=2, 3);
+        assert_eq!(buffer.text().to_string(), "a\nd\ne\nb\nc");
+        assert_eq!(lines(buffer), (vec![Some(0), Some(3), Some(4), Some(1), Some(2)], vec![0, 3, 4, 1, 2]));
+
+        let mut buffer = marked();
+        buffer.move_lines(4..=4, 0);
+        assert_eq!(buffer.text().to_string(), "e\na\nb\nc\nd");
+        assert_eq!(lines(buffer), (vec![Some(1), Some(2), Some(3), Some(4), Some(0)], vec![1, 2, 3, 4, 0]));
+    }
+
+    #[test]
+    fn marks_on_joined_lines_go_with_them() {
+        let mut buffer = marked();
+        buffer.join_lines(1);
+        assert_eq!(lines(buffer), (vec![Some(0), Some(1), Some(1), Some(2), Some(3)], vec![0, 1, 2, 3]));
+
+        // Deleting from the middle of one line into another joins them.
+        let mut buffer = marked();
+        buffer.delete_range(&Range { start: (1, 0), end: (0, 3), linewise: false });
+        assert_eq!(lines(buffer), (vec![Some(0), Some(0), Some(0), Some(0), Some(1)], vec![0, 1]));
+
+        // Lines replaced by more lines keep their marks.
+        let mut buffer = marked();
+        buffer.replace_range(&Range { start: (0, 1), end: (0, 2), linewise: true }, "x\ny\nz");
+        assert_eq!(lines(buffer), (vec![Some(0), Some(1), Some(2), Some(4), Some(5)], vec![0, 1, 2, 4, 5]));
+    }
 }
@@ -2,37 +2,217 @@ use crossterm::{ExecutableCommand, QueueableCommand};
 use crossterm::cursor;
 use crossterm::style::{self, Color, Stylize};
 use crossterm::event::{self, KeyCode, KeyModifiers};
-use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode, window_size, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
 use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::time::{Duration, SystemTime};
+use unicode_width::UnicodeWidthStr;
 
 use crate::buffer::Buffer;
+use crate::ex::{self, Address, Arg, Base, Offset};
+use crate::grapheme;
+use crate::message::{Level, Log, Message};
+use crate::operator::{self, Operator, Range, Register};
+use crate::options::{self, Options};
+use crate::search::{self, Search};
+use crate::sort::{self, Sort};
+use crate::substitute::{self, Substitute};
+use crate::statusline::{self, Status};
+use crate::swap::{self, Found};
+use crate::textobject::{self, TextObject};
+use crate::word;
+use crate::wrap;
 
 enum Action {
     Quit,
+    /// `q`, `ZQ` with `force`, or `ZZ` with `write`: quits, but not if
+    /// that would lose changes or the write fails.
+    Exit { write: bool, force: bool },
 
-    MoveUp,
-    MoveDown,
-    MoveRight,
-    MoveLeft,
+    Move(Motion, Option<usize>),
+    Operate(Operator, Motion, Option<usize>),
+    Put { before: bool, count: usize },
 
-    MoveWordForward,
-    MoveWordBackward,
-    MoveWordEnd,
+    /// Starts Visual mode, linewise or not, switches between the two, or
+    /// leaves it when it is already the kind asked for.
+    Visual(bool),
+    /// Applies an operator to the Visual selection, to whole lines if set.
+    OperateVisual(Operator, bool),
+    SwapVisualEnds,
+    SelectObject(TextObject, bool, Option<usize>),
 
-    MoveToTop,
-    MoveToBottom,
+    ScrollLines(isize),
+    ScrollHalfPage(isize),
+    ScrollPage(isize),
+    ScrollCursorTo(ScreenRow),
 
     OpenLineAbove,
     OpenLineBelow,
 
     InsertCharAtCursorPos(char),
+    InsertTab,
     DeleteChar,
-    DeleteCharAtCursorPos,
-    DeleteCurrentLine,
     NewLine,
 
+    Undo,
+    Redo,
+    UndoTo(usize),
+    UndoStep(isize),
+    UndoTravel(Duration, bool),
+
+    ShowMessages,
+
+    SetMark(char),
     EnterMode(Mode),
-    SetWaitingCmd(char),
+}
+
+/// Where the cursor goes for a motion key, and what an operator before it
+/// works on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Up,
+    Down,
+    Left,
+    Right,
+    ScreenRowUp,
+    ScreenRowDown,
+
+    /// `w`, `b`, `e` and `ge`, or for WORDs `W`, `B`, `E` and `gE`.
+    WordForward(bool),
+    WordBackward(bool),
+    WordEnd(bool),
+    WordEndBackward(bool),
+
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+
+    /// `f`, `F`, `t` or `T` and the character to look for.
+    Find(Find),
+    /// `;`, or `,` for the other way, repeating the last [`Motion::Find`].
+    RepeatFind(bool),
+    /// `n`, or `N` for the other way, and the end of a `/` or `?` search.
+    SearchNext(bool),
+    /// `*`, or `#` going back, for the word under the cursor.
+    SearchWord(bool),
+
+    FirstLine,
+    LastLine,
+    Screen(ScreenRow),
+    /// `'a` for the line of a mark, or `` `a `` for its position.
+    Mark(char, bool),
+
+    /// Lines from the cursor down, for a doubled operator as in `dd`.
+    Lines,
+    /// A text object, inner if set, as in `diw`. It selects text rather
+    /// than moving.
+    Object(TextObject, bool),
+}
+
+/// A search for a character on the cursor line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Find {
+    target: char,
+    forward: bool,
+    /// Stop next to the character rather than on it, as for `t`.
+    till: bool,
+}
+
+/// Where a `/` or `?` search started: what to go back to if it is
+/// cancelled, and what to do with it once it is entered.
+#[derive(Debug, Clone, Copy)]
+struct SearchStart {
+    forward: bool,
+    count: Option<usize>,
+    /// An operator that the search is the motion for, as in `d/foo`.
+    operator: Option<Operator>,
+    visual: bool,
+    cursor: (usize, usize),
+    vtop: usize,
+    vleft: usize,
+}
+
+/// How the text between the cursor and the end of a motion becomes a
+/// [`Range`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MotionKind {
+    /// Up to the end but not including it, as for `w`.
+    Exclusive,
+    /// Including the character at the end, as for `$`.
+    Inclusive,
+    /// Whole lines, as for `j` and `G`.
+    Linewise,
+}
+
+/// Normal mode keys typed so far for a command that isn't complete.
+#[derive(Debug, Default)]
+struct Pending {
+    count: Option<usize>,
+    /// An operator waiting for its motion, with the count typed before it.
+    operator: Option<(Operator, Option<usize>)>,
+    /// `g`, `z` or `Z`, which start two-key commands, `i` or `a` for a
+    /// text object, `f`, `F`, `t` or `T` waiting for a character, or `m`,
+    /// `'` or `` ` `` waiting for a mark.
+    prefix: Option<char>,
+}
+
+/// What `:global` keeps track of while it runs its command.
+#[derive(Debug, Default)]
+struct Global {
+    /// Whether a `:s` it ran found its pattern on any line.
+    found: bool,
+    /// The pattern of the last `:s` that found nothing on a line.
+    not_found: Option<String>,
+}
+
+/// What a key does in Normal mode.
+enum NormalKey {
+    Digit(usize),
+    Prefix(char),
+    Operator(Operator),
+    Motion(Motion),
+    /// Short for an operator and a motion, as `x` for `dl`.
+    Shorthand(Operator, Motion),
+    Put(bool),
+    /// `/`, or `?` going back.
+    Search(bool),
+    Action(Action),
+}
+
+/// How matches for the last search are drawn with `hlsearch`.
+const MATCH: style::ContentStyle = style::ContentStyle {
+    foreground_color: None,
+    background_color: Some(Color::Rgb { r: 98, g: 79, b: 132 }),
+    underline_color: None,
+    attributes: style::Attributes::none(),
+};
+
+/// How the match under the cursor, or for a search being typed, is drawn.
+const CURRENT_MATCH: style::ContentStyle = style::ContentStyle {
+    foreground_color: Some(Color::Rgb { r: 0, g: 0, b: 0 }),
+    background_color: Some(Color::Rgb { r: 184, g: 144, b: 243 }),
+    underline_color: None,
+    attributes: style::Attributes::none(),
+};
+
+/// How the Visual selection is drawn.
+const SELECTION: style::ContentStyle = style::ContentStyle {
+    foreground_color: None,
+    background_color: Some(Color::Rgb { r: 67, g: 70, b: 89 }),
+    underline_color: None,
+    attributes: style::Attributes::none(),
+};
+
+/// Changes to more lines than this are reported, like vim's default
+/// `report`.
+const REPORT: usize = 2;
+
+/// A line of the window, for `H`/`M`/`L` and `zt`/`zz`/`zb`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScreenRow {
+    Top,
+    Middle,
+    Bottom,
 }
 
 #[derive(Debug, PartialEq)]
@@ -41,19 +221,51 @@ enum Mode {
     Insert,
     Visual,
     Command,
+    /// Typing a `/` or `?` search pattern.
+    Search,
 }
 
 pub struct Editor {
     stdout: std::io::Stdout,
     buffer: Buffer,
+    /// Cursor column (in chars) and line in the buffer.
     cur_pos: (usize, usize),
-    scur_pos: Option<(usize, usize)>,
     mode: Mode,
     size: (u16, u16),
-    vtop: u16,
-    vleft: u16,
-    waiting_cmd: Option<char>,
+    /// First buffer line and first screen column in the viewport.
+    vtop: usize,
+    vleft: usize,
+    pending: Pending,
+    /// Text last deleted or yanked.
+    register: Register,
+    /// Where the Visual selection started; the cursor is the other end.
+    visual_start: (usize, usize),
+    visual_linewise: bool,
+    /// The last `f`, `F`, `t` or `T`, for `;` and `,`.
+    last_find: Option<Find>,
+    last_search: Option<Search>,
+    /// The pattern last highlighted, the `ignorecase` and `smartcase` it
+    /// was compiled with, and its regex if it is valid, so redrawing
+    /// doesn't compile it again.
+    highlighted: Option<(String, bool, bool, Option<regex::Regex>)>,
+    last_substitute: Option<Substitute>,
+    search_start: Option<SearchStart>,
+    /// The match drawn as the current one: where the search pattern being
+    /// typed matches with `incsearch`, or the one `:s///c` asks about.
+    current_match: Option<Range>,
+    /// Whether `:nohlsearch` has hidden the search highlighting until the
+    /// next search.
+    nohlsearch: bool,
+    /// Set while `:global` runs its command, which can't be another.
+    global: Option<Global>,
+    /// Whether `:normal` is typing its keys, which can't quit the editor.
+    in_normal: bool,
     command_buffer: String,
+    message: Option<Message>,
+    messages: Log,
+    /// Whether editing a readonly buffer has been warned about already.
+    readonly_warned: bool,
+    options: Options,
 }
 
 impl Editor {
@@ -63,27 +275,47 @@ impl Editor {
         stdout
             .execute(EnterAlternateScreen)?
             .execute(terminal::Clear(terminal::ClearType::All))?;
+        Ok(Self::with_size(buffer, terminal::size()?))
+    }
 
-        Ok(Editor {
+    /// An editor for `buffer` on a screen of `size`, without setting up
+    /// the terminal.
+    fn with_size(buffer: Buffer, size: (u16, u16)) -> Self {
+        Editor {
             stdout: io::stdout(),
             buffer,
             cur_pos: (0, 0),
-            scur_pos: None,
-            size: terminal::size()?,
+            size,
             mode: Mode::Normal,
             vtop: 0,
             vleft: 0,
-            waiting_cmd: None,
+            pending: Pending::default(),
+            register: Register::default(),
+            visual_start: (0, 0),
+            visual_linewise: false,
+            last_find: None,
+            last_search: None,
+            highlighted: None,
+            last_substitute: None,
+            search_start: None,
+            current_match: None,
+            nohlsearch: false,
+            global: None,
+            in_normal: false,
             command_buffer: String::new(),
-        })
+            message: None,
+            messages: Log::default(),
+            readonly_warned: false,
+            options: Options::default(),
+        }
     }
 
     fn vwidth(&self) -> u16 {
-        self.size.0 - self.line_number_width() - 1
+        self.size.0.saturating_sub(self.line_number_width() + 2)
     }
 
     fn vheight(&self) -> u16 {
-        self.size.1 - 2
+        self.size.1.saturating_sub(2)
     }
 
     fn line_number_width(&self) -> u16 {
@@ -91,63 +323,316 @@ impl Editor {
         (total_lines.to_string().len()).max(3) as u16 + 2
     }
 
-    fn line_length(&self) -> u16 {
-        if let Some(line) = self.viewport_line(self.cur_pos.1 as u16) {
-            return line.len() as u16;
+    /// Screen column of the cursor within its line, before scrolling.
+    fn cursor_col(&self) -> usize {
+        self.buffer.display_col(self.cur_pos.0, self.cur_pos.1, self.options.tabstop)
+    }
+
+    /// Moves the cursor to line `y`, keeping it in the same screen column.
+    fn move_to_line(&mut self, y: usize) {
+        let col = self.cursor_col();
+        self.cur_pos.1 = y;
+        self.cur_pos.0 = self.buffer.col_to_char(col, self.cur_pos.1, self.options.tabstop);
+    }
+
+    /// `scrolloff`, limited to what fits in the window.
+    fn scrolloff(&self) -> usize {
+        self.options.scrolloff.min((self.vheight().max(1) as usize - 1) / 2)
+    }
+
+    /// How line `y` is split into screen rows. Without `wrap` it is one
+    /// row, however long.
+    fn rows_of(&self, y: usize) -> Vec<wrap::Row> {
+        let Some(line) = self.buffer.get(y) else { return vec![wrap::Row { start: 0, end: 0, vcol: 0 }] };
+        if !self.options.wrap {
+            return vec![wrap::Row { start: 0, end: line.len_chars(), vcol: 0 }];
+        }
+        let indent = self.options.showbreak.width();
+        wrap::rows(line, self.vwidth().max(1) as usize, indent, self.options.tabstop)
+    }
+
+    fn row_count(&self, y: usize) -> usize {
+        if self.options.wrap { self.rows_of(y).len() } else { 1 }
+    }
+
+    /// The first line of a viewport that shows at most `room` screen rows
+    /// above `line`.
+    fn top_with_rows_above(&self, line: usize, room: usize) -> usize {
+        let (mut top, mut used) = (line, 0);
+        while top > 0 {
+            let rows = self.row_count(top - 1);
+            if used + rows > room {
+                break;
+            }
+            used += rows;
+            top -= 1;
+        }
+        top
+    }
+
+    /// Last line that fits on screen in full, or the top line if even that
+    /// doesn't.
+    fn bottom_line(&self) -> usize {
+        let height = self.vheight().max(1) as usize;
+        let last = self.buffer.len() - 1;
+        let (mut y, mut used) = (self.vtop, self.row_count(self.vtop));
+        while y < last && used + self.row_count(y + 1) <= height {
+            y += 1;
+            used += self.row_count(y);
+        }
+        y
+    }
+
+    /// Scrolls the viewport so the cursor is on screen, with `scrolloff`
+    /// lines above and below it and `sidescrolloff` columns to either side
+    /// where the buffer allows.
+    fn scroll(&mut self) {
+        let height = self.vheight().max(1) as usize;
+        let so = self.scrolloff();
+        let line = self.cur_pos.1;
+        let last = (line + so).min(self.buffer.len() - 1);
+        let below: usize = (line..=last).map(|y| self.row_count(y)).sum();
+        let min_top = self.top_with_rows_above(line, height.saturating_sub(below));
+        if line < self.vtop + so {
+            self.vtop = line.saturating_sub(so);
+        } else if self.vtop < min_top {
+            self.vtop = min_top;
+        }
+        self.vtop = self.vtop.min(self.buffer.len() - 1);
+
+        if self.options.wrap {
+            self.vleft = 0;
+            return;
+        }
+        let width = self.vwidth().max(1) as usize;
+        let so = self.options.sidescrolloff.min((width - 1) / 2);
+        let col = self.cursor_col();
+        if col < self.vleft + so {
+            self.vleft = col.saturating_sub(so);
+        } else if col + so >= self.vleft + width {
+            self.vleft = col + so + 1 - width;
+        }
+    }
+
+    /// First and last lines the cursor may be on without the viewport
+    /// having to scroll, keeping `scrolloff` lines of context except at the
+    /// ends of the buffer.
+    fn cursor_bounds(&self) -> (usize, usize) {
+        let so = self.scrolloff();
+        let last = self.buffer.len() - 1;
+        let bottom = self.bottom_line();
+        let first = if self.vtop == 0 { 0 } else { self.vtop + so };
+        let end = if bottom == last { last } else { bottom.saturating_sub(so) };
+        (first.min(end), end)
+    }
+
+    /// Moves the cursor back into the viewport after it was scrolled.
+    fn follow_viewport(&mut self) {
+        let (first, last) = self.cursor_bounds();
+        let line = self.cur_pos.1.clamp(first, last);
+        if line != self.cur_pos.1 {
+            self.move_to_line(line);
         }
-        0
     }
 
-    fn buffer_line(&self) -> u16 {
-        self.vtop + self.cur_pos.1 as u16
+    /// Moves the cursor to the first non-blank of `line`.
+    fn move_to_line_start(&mut self, line: usize) {
+        self.cur_pos = (self.buffer.first_non_blank(line), line);
     }
 
-    fn viewport_line(&self, n: u16) -> Option<String> {
-        let buffer_line = self.vtop + n;
-        self.buffer.get(buffer_line as usize)
+    /// Where `gj`/`gk` go from `(x, y)`: one screen row down or up, in the
+    /// same screen column. The same as `j`/`k` without `wrap`.
+    fn screen_row_target(&self, (x, y): (usize, usize), down: bool) -> Option<(usize, usize)> {
+        let ts = self.options.tabstop;
+        let rows = self.rows_of(y);
+        let i = rows.iter().rposition(|r| r.start <= x).unwrap_or(0);
+        let offset = self.buffer.display_col(x, y, ts) - rows[i].vcol;
+
+        let (y, row, last) = match (down, i) {
+            (true, _) if i + 1 < rows.len() => (y, rows[i + 1], i + 2 == rows.len()),
+            (true, _) if y + 1 < self.buffer.len() => {
+                let rows = self.rows_of(y + 1);
+                (y + 1, rows[0], rows.len() == 1)
+            }
+            (false, 1..) => (y, rows[i - 1], false),
+            (false, 0) if y > 0 => {
+                let rows = self.rows_of(y - 1);
+                (y - 1, rows[rows.len() - 1], true)
+            }
+            _ => return None,
+        };
+        let x = self.buffer.col_to_char(row.vcol + offset, y, ts);
+        // The end of a row that isn't the last belongs to the next row.
+        let x = if last || x < row.end { x } else { self.buffer.prev_grapheme(row.end, y) };
+        Some((x.max(row.start), y))
+    }
+
+    /// The screen rows of the viewport: a buffer line and the part of it on
+    /// that row.
+    fn layout(&self) -> Vec<(usize, wrap::Row)> {
+        let height = self.vheight() as usize;
+        let mut layout = Vec::with_capacity(height);
+        for y in self.vtop..self.buffer.len() {
+            for row in self.rows_of(y) {
+                if layout.len() == height {
+                    return layout;
+                }
+                layout.push((y, row));
+            }
+        }
+        layout
     }
 
     pub fn draw(&mut self) -> io::Result<()> {
-        self.draw_viewport()?;
+        self.scroll();
+        let layout = self.layout();
+        self.draw_viewport(&layout)?;
         self.draw_statusline()?;
-        self.draw_line_numbers()?;
-        if matches!(self.mode, Mode::Command) {
+        self.draw_line_numbers(&layout)?;
+        if matches!(self.mode, Mode::Command | Mode::Search) {
             self.draw_commandline()?;
         } else {
-            let x_offset = self.line_number_width() + 2;
-
-            self.stdout.queue(cursor::MoveTo(self.cur_pos.0 as u16 + x_offset, self.cur_pos.1 as u16))?;
+            self.draw_message()?;
+            let (x, y) = self.cursor_screen_pos(&layout);
+            self.stdout.queue(cursor::MoveTo(x, y))?;
         }
         self.stdout.flush()?;
 
         Ok(())
     }
 
-    pub fn draw_viewport(&mut self) -> io::Result<()> {
+    /// Where the cursor is drawn.
+    fn cursor_screen_pos(&self, layout: &[(usize, wrap::Row)]) -> (u16, u16) {
+        let (x, y) = self.cur_pos;
+        let x_offset = self.line_number_width() as usize + 2;
+        let Some(row) = layout.iter().rposition(|(line, row)| *line == y && row.start <= x) else {
+            return (x_offset as u16, 0);
+        };
+        let (_, first_row) = layout[row];
+        let indent = if first_row.start > 0 { self.options.showbreak.width() } else { 0 };
+        let col = indent + self.cursor_col() - first_row.vcol - self.vleft;
+        // Past the end of a full row, as after typing at the end of a line.
+        if self.options.wrap && col >= self.vwidth() as usize && row + 1 < self.vheight() as usize {
+            return ((x_offset + self.options.showbreak.width()) as u16, row as u16 + 1);
+        }
+        let col = col.min(self.vwidth().saturating_sub(1) as usize);
+        ((x_offset + col) as u16, row as u16)
+    }
+
+    pub fn draw_viewport(&mut self, layout: &[(usize, wrap::Row)]) -> io::Result<()> {
         let vwidth = self.vwidth() as usize;
         let start_point = self.line_number_width() + 2;
+        let visual = self.mode == Mode::Visual || self.search_start.is_some_and(|start| start.visual);
+        let selection = visual.then(|| self.visual_range());
+        let selected = |pos| selection.is_some_and(|range| range.contains(pos));
+        let matches = self.visible_matches(layout);
+        let matched = |(x, y): (usize, usize)| {
+            let (_, line) = matches.iter().find(|(line, _)| *line == y)?;
+            line.iter().find(|(start, end)| (start..end).contains(&&x)).copied()
+        };
+        // Otherwise the match the cursor is on.
+        let current = self.current_match.or_else(|| {
+            let (start, end) = matched(self.cur_pos)?;
+            let y = self.cur_pos.1;
+            Some(Range { start: (start, y), end: (end, y), linewise: false })
+        });
         for i in 0..self.vheight() {
-            let line = self.viewport_line(i).unwrap_or_default();
+            self.stdout.queue(cursor::MoveTo(start_point, i))?;
 
-            self.stdout
-                .queue(cursor::MoveTo(start_point, i))?
-                .queue(style::Print(format!("{line:<width$}", width = vwidth)))?;
+            let mut printed = 0;
+            if let Some(&(y, row)) = layout.get(i as usize) {
+                if row.start > 0 {
+                    let showbreak: String = self.options.showbreak.chars().take(vwidth).collect();
+                    printed += showbreak.width();
+                    self.stdout.queue(style::PrintStyledContent(showbreak.with(Color::Rgb { r: 128, g: 128, b: 128 })))?;
+                }
+                let line = self.buffer.get(y).map(|l| l.slice(row.start..row.end));
+                let (mut x, mut col) = (row.start, row.vcol);
+                for g in line.into_iter().flat_map(grapheme::graphemes) {
+                    let width = grapheme::width(g, col, self.options.tabstop);
+                    let style = if current.is_some_and(|m| m.contains((x, y))) {
+                        CURRENT_MATCH
+                    } else if selected((x, y)) {
+                        SELECTION
+                    } else if matched((x, y)).is_some() {
+                        MATCH
+                    } else {
+                        style::ContentStyle::new()
+                    };
+                    x += g.len_chars();
+                    col += width;
+                    if col <= self.vleft {
+                        continue;
+                    }
+                    // Only part of a tab or wide character is scrolled into
+                    // view; show the visible part as blanks.
+                    let visible = width.min(col - self.vleft);
+                    if printed + visible > vwidth {
+                        break;
+                    }
+                    let text = if g.char(0) == '\t' || visible < width {
+                        format!("{:visible$}", "")
+                    } else {
+                        grapheme::display(g).into_owned()
+                    };
+                    self.stdout.queue(style::PrintStyledContent(style.apply(text)))?;
+                    printed += visible;
+                }
+                // A selected line break shows as a selected blank.
+                let line_end = row.end == self.buffer.line_len(y);
+                if line_end && selected((row.end, y)) && col >= self.vleft && printed < vwidth {
+                    self.stdout.queue(style::PrintStyledContent(SELECTION.apply(" ")))?;
+                    printed += 1;
+                }
+            }
+            self.stdout.queue(style::Print(format!("{:<width$}", "", width = vwidth - printed)))?;
         }
         Ok(())
     }
 
 
-    pub fn draw_line_numbers(&mut self) -> io::Result<()> {
+    /// Matches to highlight on the lines in view, by line: for the last
+    /// search with `hlsearch`, or for the pattern being typed with
+    /// `incsearch` too.
+    fn visible_matches(&mut self, layout: &[(usize, wrap::Row)]) -> Vec<(usize, Vec<(usize, usize)>)> {
+        let pattern = match &self.last_search {
+            _ if self.mode == Mode::Search && self.options.incsearch => self.command_buffer.as_str(),
+            Some(search) => search.pattern.as_str(),
+            None => return Vec::new(),
+        };
+        if !self.options.hlsearch || (self.nohlsearch && self.mode != Mode::Search) || pattern.is_empty() {
+            return Vec::new();
+        }
+        let (ignorecase, smartcase) = (self.options.ignorecase, self.options.smartcase);
+        let regex = match &self.highlighted {
+            Some((last, i, s, regex)) if last == pattern && (*i, *s) == (ignorecase, smartcase) => regex.clone(),
+            _ => {
+                let regex = self.search_regex(pattern).ok();
+                self.highlighted = Some((pattern.to_string(), ignorecase, smartcase, regex.clone()));
+                regex
+            }
+        };
+        let Some(regex) = regex else { return Vec::new() };
+        let mut lines: Vec<usize> = layout.iter().map(|&(y, _)| y).collect();
+        lines.dedup();
+        lines.into_iter().map(|y| (y, search::matches(&self.buffer, &regex, y))).collect()
+    }
+
+    pub fn draw_line_numbers(&mut self, layout: &[(usize, wrap::Row)]) -> io::Result<()> {
         let line_number_width = self.line_number_width();
-        let editor_border_y = self.vheight().min(self.buffer.len() as u16);
-        for line_number in 0..self.vheight() {
-            let current_line = if line_number >= editor_border_y {
-                format!("~{:>width$} ", "", width = line_number_width as usize)
-            } else {
-                format!(" {:>width$} ", line_number + 1, width = line_number_width as usize)
+        for row in 0..self.vheight() {
+            let current_line = match layout.get(row as usize) {
+                None => format!("~{:>width$} ", "", width = line_number_width as usize),
+                // A wrapped line continues on this row.
+                Some((_, wrapped)) if wrapped.start > 0 => {
+                    format!(" {:>width$} ", "↪", width = line_number_width as usize)
+                }
+                Some((line_number, _)) => {
+                    format!(" {:>width$} ", line_number + 1, width = line_number_width as usize)
+                }
             };
 
-            self.stdout.queue(cursor::MoveTo(0, line_number))?;
+            self.stdout.queue(cursor::MoveTo(0, row))?;
             self.stdout.queue(style::PrintStyledContent(
                 current_line.with(Color::Rgb { r: 128, g: 128, b: 128 })
                     .bold(),
@@ -157,11 +642,27 @@ impl Editor {
     }
 
     pub fn draw_statusline(&mut self) -> io::Result<()> {
-        let mode = format!(" {:?} ", self.mode).to_uppercase();
-        let file = " src/main.rs";
-        let pos = format!(" {}:{} ", self.cur_pos.0 + 1, self.cur_pos.1 + 1);
+        let mode = match self.mode {
+            Mode::Visual if self.visual_linewise => " VISUAL LINE ".to_string(),
+            _ => format!(" {:?} ", self.mode).to_uppercase(),
+        };
+        let (x, line) = self.cur_pos;
+        let top = self.vtop;
+        let format = self.options.statusline.clone();
+        let mut status = Status {
+            line,
+            col: x,
+            vcol: self.buffer.display_col(x, line, self.options.tabstop),
+            top,
+            bottom: self.bottom_line(),
+            buffer: &mut self.buffer,
+            options: &mut self.options,
+        };
+        let (file, pos) = statusline::render(&format, &mut status);
 
-        let file_width = self.size.0 - mode.len() as u16 - pos.len() as u16 - 2;
+        let width = (self.size.0 as usize).saturating_sub(mode.len() + 2);
+        let pos = statusline::fit(&pos, pos.width().min(width));
+        let file = statusline::fit(&file, width - pos.width());
 
         self.stdout.queue(cursor::MoveTo(0, self.size.1 - 2))?;
         self.stdout.queue(style::PrintStyledContent(
@@ -175,8 +676,7 @@ impl Editor {
                 .on(Color::Rgb { r: 67, g: 70, b: 89 }),
         ))?;
         self.stdout.queue(style::PrintStyledContent(
-            format!("{:<width$}", file, width = file_width as usize)
-                .with(Color::Rgb { r: 255, g: 255, b: 255 })
+            file.with(Color::Rgb { r: 255, g: 255, b: 255 })
                 .bold()
                 .on(Color::Rgb { r: 67, g: 70, b: 89 }),
         ))?;
@@ -195,7 +695,11 @@ impl Editor {
     }
 
     fn draw_commandline(&mut self) -> io::Result<()> {
-        let cmd = format!(":{}", self.command_buffer);
+        let prompt = match self.search_start {
+            Some(start) if self.mode == Mode::Search => if start.forward { '/' } else { '?' },
+            _ => ':',
+        };
+        let cmd = format!("{prompt}{}", self.command_buffer);
         let vwidth = self.vwidth() as usize;
         self.stdout
             .queue(cursor::MoveTo(0, self.size.1 - 1))?
@@ -208,6 +712,39 @@ impl Editor {
         Ok(())
     }
 
+    /// Shows `message` on the command line row until the next keypress,
+    /// and keeps it for `:messages`.
+    pub fn show(&mut self, message: Message) {
+        self.messages.push(message.clone());
+        self.message = Some(message);
+    }
+
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.show(Message { level: Level::Info, text: text.into() });
+    }
+
+    pub fn warn(&mut self, text: impl Into<String>) {
+        self.show(Message { level: Level::Warning, text: text.into() });
+    }
+
+    pub fn error(&mut self, text: impl Into<String>) {
+        self.show(Message { level: Level::Error, text: text.into() });
+    }
+
+    fn draw_message(&mut self) -> io::Result<()> {
+        let width = self.size.0 as usize;
+        let (text, style) = match &self.message {
+            Some(message) => (message.text.chars().take(width).collect(), message.style()),
+            None => (String::new(), style::ContentStyle::new()),
+        };
+        let padding = width - text.chars().count();
+        self.stdout
+            .queue(cursor::MoveTo(0, self.size.1 - 1))?
+            .queue(style::PrintStyledContent(style.apply(text)))?
+            .queue(style::Print(format!("{:padding$}", "")))?;
+        Ok(())
+    }
+
     pub fn clear_command(&mut self) -> io::Result<()> {
         let vwidth = self.vwidth() as usize;
         self.stdout
@@ -220,314 +757,1946 @@ impl Editor {
     pub fn run(&mut self) -> io::Result<()> {
         loop {
             self.draw()?;
+            let idle = Duration::from_millis(self.options.updatetime as u64);
+            if !event::poll(idle)? {
+                self.write_swap();
+                continue;
+            }
+
+            let tick = self.buffer.tick();
             if let Some(action) = self.handle_event(event::read()?)? {
-                match action {
-                    Action::Quit => break,
-                    Action::MoveUp => {
-                        self.cur_pos.1 = self.cur_pos.1.saturating_sub(1);
-                        self.cur_pos.0 = self.cur_pos.0.min(self.buffer.lines[self.cur_pos.1].len());
-                    }
-                    Action::MoveDown => {
-                        if self.cur_pos.1.saturating_add(1) < self.buffer.lines.len(){
-                            self.cur_pos.1 += 1;
-                            self.cur_pos.0 = self.cur_pos.0.min(self.buffer.lines[self.cur_pos.1].len());
-                        }
-                        if self.cur_pos.1 >= self.vheight() as usize {
-                            self.cur_pos.1 = (self.vheight() - 1) as usize;
-                        }
-                    },
-                    Action::MoveLeft => {
-                        self.cur_pos.0 = self.cur_pos.0.saturating_sub(1);
-                        if self.cur_pos.0 < self.vleft as usize {
-                            self.cur_pos.0 = self.vleft as usize;
-                        }
-                    },
-                    Action::MoveRight => {
-                        self.cur_pos.0 += 1;
-                        if self.cur_pos.0 >= self.line_length() as usize {
-                            self.cur_pos.0 = self.line_length() as usize;
-                        }
-                        if self.cur_pos.0 >= self.vwidth() as usize {
-                            self.cur_pos.0 = (self.vwidth() - 1) as usize;
-                        }
-                    },
-                    Action::MoveWordForward => {
-                        // TODO: Needs fixing
-                        let line = &mut self.buffer.lines[self.cur_pos.1];
-                        if let Some(pos) = line[self.cur_pos.0..].find(|c: char| c.is_whitespace()) {
-                            self.cur_pos.0 += pos + 1;
-                        } else {
-                            self.cur_pos.0 = line.len();
-                        }
-                    },
-                    Action::MoveWordBackward => {
-                        // TODO: Needs fixing
-                        let line = &mut self.buffer.lines[self.cur_pos.1];
-                        if let Some(pos) = line[..self.cur_pos.0].rfind(|c: char| c.is_whitespace()) {
-                            self.cur_pos.0 = pos;
-                        } else {
-                            self.cur_pos.0 = line.len();
-                        }
-                    },
-                    Action::MoveWordEnd => {
-                        self.cur_pos.0 = self.buffer.lines[self.cur_pos.1].len()-1;
-                    },
-                    Action::MoveToTop => {
-                        self.cur_pos.0 = 0;
-                        self.cur_pos.1 = 0;
-                    },
-                    Action::MoveToBottom => {
-                        self.cur_pos.0 = 0;
-                        self.cur_pos.1 = self.buffer.lines.len() - 1;
-                    },
-                    Action::OpenLineAbove => {
-                        self.buffer.lines.insert(self.cur_pos.1, String::new());
-                        self.mode = Mode::Insert;
-                        self.cur_pos.0 = 0;
-                    },
-                    Action::OpenLineBelow => {
-                        self.buffer.lines.insert(self.cur_pos.1 + 1, String::new());
-                        self.mode = Mode::Insert;
-                        self.cur_pos.1 += 1;
-                        self.cur_pos.0 = 0;
-                    },
-                    Action::InsertCharAtCursorPos(c) => {
-                        self.buffer.insert(self.cur_pos.0 as u16, self.buffer_line(), c);
-                        self.cur_pos.0 += 1;
-                    },
-                    Action::DeleteChar => {
-                        if self.cur_pos.0 == 0 && self.cur_pos.1 > 0 {
-                            let current_line = self.buffer.lines.remove(self.cur_pos.1);
-                            self.cur_pos.1 -= 1;
-                            let prev_line = &mut self.buffer.lines[self.cur_pos.1];
-                            self.cur_pos.0 = prev_line.len();
-                            prev_line.push_str(&current_line);
-                        } else if let Some(line) = self.buffer.lines.get_mut(self.cur_pos.1) {
-                            if self.cur_pos.0 < line.len() { line.remove(self.cur_pos.0 - 1); }
-                            else { line.pop(); }
-                            self.cur_pos.0 = self.cur_pos.0.saturating_sub(1);
-                        }
-                        self.stdout.queue(cursor::MoveTo(self.cur_pos.0 as u16, self.cur_pos.1 as u16))?;
-                    },
-                    Action::DeleteCharAtCursorPos => {
-                        self.buffer.delete(self.cur_pos.0 as u16, self.buffer_line());
-                    }
-                    Action::DeleteCurrentLine => {
-                        self.buffer.remove_line(self.buffer_line());
-                        self.cur_pos.1 = self.cur_pos.1.saturating_sub(1);
-                    },
-                    Action::NewLine => {
-                        if self.cur_pos.1 >= self.buffer.lines.len() {
-                            self.buffer.lines.push(String::new());
-                        }
-                        let line = self.buffer.lines[self.cur_pos.1].clone();
-                        if self.cur_pos.0 < line.len() {
-                            let (left, right) = line.split_at(self.cur_pos.0);
-                            self.buffer.lines[self.cur_pos.1] = left.to_string();
-                            self.buffer.lines.insert(self.cur_pos.1 + 1, right.to_string());
-                        } else {
-                            self.buffer.lines.insert(self.cur_pos.1 + 1, String::new());
-                        }
-                        self.cur_pos.0 = 0;
-                        self.cur_pos.1 += 1;
-                    },
-                    Action::EnterMode(new_mode) => {
-                        if matches!(new_mode, Mode::Normal) {
-                            match self.mode {
-                                Mode::Command => {
-                                    self.cur_pos = self.scur_pos.unwrap();
-                                    self.scur_pos = None;
-                                    self.command_buffer.clear();
-                                    self.clear_command()?;
-                                },
-                                Mode::Insert => {
-                                    self.cur_pos = (self.cur_pos.0.saturating_sub(1), self.cur_pos.1)
-                                },
-                                _ => {}
-                            }
-                        } else if matches!(new_mode, Mode::Command) {
-                            self.scur_pos = Some(self.cur_pos);
-                            self.cur_pos.0 = 0;
-                            self.cur_pos.1 = (self.size.1 - 1) as usize;
-                        };
-                        self.mode = new_mode;
-                    },
-                    Action::SetWaitingCmd(cmd) => {
-                        self.waiting_cmd = Some(cmd);
-                    },
+                if self.perform(action)? {
+                    break;
                 }
             }
-        }
 
-        Ok(())
-    }
+            // Everything done outside of Insert mode is its own undo step;
+            // an insert session is committed as a whole once it ends.
+            if self.mode != Mode::Insert {
+                self.buffer.commit();
+            }
 
-    fn handle_event(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
-        if matches!(ev, event::Event::Resize(_, _)) {
-            self.size = terminal::size()?;
-        }
+            if self.buffer.tick() != tick && self.buffer.readonly && !self.readonly_warned {
+                self.readonly_warned = true;
+                self.warn("W10: Warning: Changing a readonly file");
+            }
 
-        match self.mode {
-            Mode::Normal => self.handle_normal_mode(ev),
-            Mode::Insert => self.handle_insert_mode(ev),
-            Mode::Visual => self.handle_visual_mode(ev),
-            Mode::Command => self.handle_command_mode(ev),
+            if self.options.updatecount > 0 {
+                swap::remember(self.buffer.snapshot());
+                if self.buffer.changes_since_swap() >= self.options.updatecount {
+                    self.write_swap();
+                }
+            }
         }
-    }
 
-    // Normal Mode
-    fn handle_normal_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
-        if let Some(cmd) = self.waiting_cmd {
-            self.waiting_cmd = None;
-            return self.handle_waiting_cmd(cmd, ev);
-        }
+        Ok(())
+    }
 
-        let action = match ev {
-            event::Event::Key(event) => {
-                let code = event.code;
-                let _modifiers = event.modifiers;
-
-                match code {
-                    KeyCode::Char('q') => Some(Action::Quit),
-                    KeyCode::Up | KeyCode::Char('k') => Some(Action::MoveUp),
-                    KeyCode::Down | KeyCode::Char('j') => Some(Action::MoveDown),
-                    KeyCode::Right | KeyCode::Char('l') => Some(Action::MoveRight),
-                    KeyCode::Left | KeyCode::Char('h') => Some(Action::MoveLeft),
-                    KeyCode::Char('w') => Some(Action::MoveWordForward),
-                    KeyCode::Char('b') => Some(Action::MoveWordBackward),
-                    KeyCode::Char('$') => Some(Action::MoveWordEnd),
-                    KeyCode::Char('G') => Some(Action::MoveToBottom),
-                    KeyCode::Char('O') => Some(Action::OpenLineAbove),
-                    KeyCode::Char('o') => Some(Action::OpenLineBelow),
-                    KeyCode::Char('x') => Some(Action::DeleteCharAtCursorPos),
-                    KeyCode::Char('i') => Some(Action::EnterMode(Mode::Insert)),
-                    KeyCode::Char('a') => {
-                        self.cur_pos.0 += 1;
-                        Some(Action::EnterMode(Mode::Insert))
-                    },
-                    KeyCode::Char('v') => Some(Action::EnterMode(Mode::Visual)),
-                     KeyCode::Char(':') => Some(Action::EnterMode(Mode::Command)),
-                    KeyCode::Char('d') => Some(Action::SetWaitingCmd('d')),
-                    KeyCode::Char('g') => Some(Action::SetWaitingCmd('g')),
-                    _ => None,
+    /// Carries out `action`. Returns `true` if it quits the editor.
+    fn perform(&mut self, action: Action) -> io::Result<bool> {
+        match action {
+            Action::Quit | Action::Exit { .. } if self.in_normal => {},
+            Action::Quit => return Ok(true),
+            Action::Exit { write, force } => {
+                let action = if write { self.write_and_quit(force, true) } else { self.quit(force) };
+                return Ok(action.is_some());
+            },
+            Action::Move(motion, count) => {
+                if let Some((pos, _)) = self.motion_target(motion, count) {
+                    self.cur_pos = pos;
                 }
             },
-            _ => None,
-        };
-
-        Ok(action)
-    }
-
-    fn handle_visual_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
-        let action = match ev {
-            event::Event::Key(event) => match (event.code, event.modifiers) {
-                (KeyCode::Char('c'), KeyModifiers::CONTROL) |
-                (KeyCode::Esc, _) => Some(Action::EnterMode(Mode::Normal)),
-                (KeyCode::Char('h'), _) => Some(Action::MoveLeft),
-                (KeyCode::Char('j'), _) => Some(Action::MoveDown),
-                (KeyCode::Char('k'), _) => Some(Action::MoveUp),
-                (KeyCode::Char('l'), _) => Some(Action::MoveRight),
-                _ => None,
+            Action::Operate(op, motion, count) => {
+                if let Some(range) = self.motion_range(op, motion, count) {
+                    self.operate(op, range);
+                }
             },
-            _ => None,
-        };
-
-        Ok(action)
-    }
-
-    fn handle_command_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
-        let action = match ev {
-            event::Event::Key(event) => match (event.code, event.modifiers) {
-                (KeyCode::Char('c'), KeyModifiers::CONTROL) |
-                (KeyCode::Esc, _) => {
-                    self.command_buffer.clear();
-                    Some(Action::EnterMode(Mode::Normal))
-                },
-                (KeyCode::Char(c), _) => {
-                    self.command_buffer.push(c);
-                    None
-                },
-                (KeyCode::Backspace, _) => {
-                    if self.command_buffer.is_empty() { Some(Action::EnterMode(Mode::Normal)) }
-                    else {
-                        self.command_buffer.pop();
-                        None
+            Action::Put { before, count } => self.put(before, count),
+            Action::Visual(linewise) => {
+                if self.mode != Mode::Visual {
+                    self.mode = Mode::Visual;
+                    self.visual_start = self.cur_pos;
+                } else if self.visual_linewise == linewise {
+                    self.set_visual_marks();
+                    self.mode = Mode::Normal;
+                }
+                self.visual_linewise = linewise;
+            },
+            Action::OperateVisual(op, linewise) => {
+                let mut range = self.visual_range();
+                range.linewise |= linewise;
+                self.set_visual_marks();
+                self.mode = Mode::Normal;
+                self.operate(op, range);
+            },
+            Action::SwapVisualEnds => std::mem::swap(&mut self.visual_start, &mut self.cur_pos),
+            Action::SelectObject(object, inner, count) => self.select_object(object, inner, count),
+            Action::ScrollLines(count) => {
+                let last = self.buffer.len() - 1;
+                self.vtop = self.vtop.saturating_add_signed(count).min(last);
+                self.follow_viewport();
+            },
+            Action::ScrollHalfPage(direction) => {
+                let height = self.vheight().max(1) as usize;
+                let amount = match self.options.scroll {
+                    0 => (height / 2).max(1),
+                    n => n,
+                };
+                let last = self.buffer.len() - 1;
+                if direction > 0 {
+                    // Stop scrolling once the end of the buffer is in view.
+                    let max_top = self.top_with_rows_above(last, height - 1).max(self.vtop);
+                    self.vtop = (self.vtop + amount).min(max_top);
+                    self.move_to_line_start((self.cur_pos.1 + amount).min(last));
+                } else {
+                    self.vtop = self.vtop.saturating_sub(amount);
+                    self.move_to_line_start(self.cur_pos.1.saturating_sub(amount));
+                }
+            },
+            Action::ScrollPage(direction) => {
+                // Two lines of the previous page stay visible.
+                let amount = (self.vheight() as usize).saturating_sub(2).max(1);
+                let last = self.buffer.len() - 1;
+                if direction > 0 {
+                    self.vtop = (self.vtop + amount).min(last);
+                } else {
+                    self.vtop = self.vtop.saturating_sub(amount);
+                }
+                let (first, end) = self.cursor_bounds();
+                self.move_to_line_start(self.cur_pos.1.clamp(first, end));
+            },
+            Action::ScrollCursorTo(row) => {
+                let height = self.vheight().max(1) as usize;
+                let so = self.scrolloff();
+                let line = self.cur_pos.1;
+                let rows = self.row_count(line);
+                self.vtop = match row {
+                    ScreenRow::Top => line.saturating_sub(so),
+                    ScreenRow::Middle => self.top_with_rows_above(line, height.saturating_sub(rows) / 2),
+                    ScreenRow::Bottom => {
+                        let last = (line + so).min(self.buffer.len() - 1);
+                        let below: usize = (line..=last).map(|y| self.row_count(y)).sum();
+                        self.top_with_rows_above(line, height.saturating_sub(below))
                     }
-                },
-                (KeyCode::Enter, _) => {
-                    let cmd = self.command_buffer.trim().to_string();
-                    self.command_buffer.clear();
-                    self.process_command(cmd)
-                },
-                _ => None,
+                };
             },
-            _ => None,
-        };
-
-        Ok(action)
-    }
-
-    fn handle_insert_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
-        let action = match ev {
-            event::Event::Key(event) => match (event.code, event.modifiers) {
-                (KeyCode::Char('c'), KeyModifiers::CONTROL) |
-                (KeyCode::Esc, _)=> Some(Action::EnterMode(Mode::Normal)),
-                (KeyCode::Char(c), _) => Some(Action::InsertCharAtCursorPos(c)),
-                (KeyCode::Enter, _) => Some(Action::NewLine),
-                (KeyCode::Backspace, _) => {
-                    Some(Action::DeleteChar)
+            Action::OpenLineAbove => {
+                self.buffer.insert_line(self.cur_pos.1, "");
+                self.mode = Mode::Insert;
+                self.cur_pos.0 = 0;
+            },
+            Action::OpenLineBelow => {
+                self.buffer.insert_line(self.cur_pos.1 + 1, "");
+                self.mode = Mode::Insert;
+                self.cur_pos.1 += 1;
+                self.cur_pos.0 = 0;
+            },
+            Action::InsertCharAtCursorPos(c) => {
+                let line = self.cur_pos.1;
+                self.buffer.insert(self.cur_pos.0, line, c);
+                // A combining character joins the grapheme before it.
+                self.cur_pos.0 = self.buffer.next_grapheme(self.cur_pos.0, line);
+            },
+            Action::InsertTab => {
+                let line = self.cur_pos.1;
+                let (start, text) = self.tab_text();
+                while self.cur_pos.0 > start {
+                    self.cur_pos.0 -= 1;
+                    self.buffer.delete(self.cur_pos.0, line);
                 }
-                _ => None,
+                self.buffer.insert_str(self.cur_pos.0, line, &text);
+                self.cur_pos.0 += text.chars().count();
             },
-            _ => None,
-        };
+            Action::DeleteChar => {
+                if self.delete_soft_tab() {
+                    // Handled as a whole soft tab.
+                } else if self.cur_pos.0 == 0 && self.cur_pos.1 > 0 {
+                    self.cur_pos.1 -= 1;
+                    self.cur_pos.0 = self.buffer.line_len(self.cur_pos.1);
+                    self.buffer.join_lines(self.cur_pos.1);
+                } else if self.cur_pos.0 > 0 {
+                    self.cur_pos.0 = self.buffer.prev_grapheme(self.cur_pos.0, self.cur_pos.1);
+                    self.buffer.delete(self.cur_pos.0, self.cur_pos.1);
+                }
+            },
+            Action::NewLine => {
+                if self.cur_pos.1 >= self.buffer.len() {
+                    self.buffer.insert_line(self.buffer.len(), "");
+                }
+                self.buffer.split_line(self.cur_pos.0, self.cur_pos.1);
+                self.cur_pos.0 = 0;
+                self.cur_pos.1 += 1;
+            },
+            Action::Undo => {
+                let pos = self.buffer.undo();
+                if pos.is_none() {
+                    self.info("Already at oldest change");
+                }
+                self.restore_cursor(pos);
+            },
+            Action::Redo => {
+                let pos = self.buffer.redo();
+                if pos.is_none() {
+                    self.info("Already at newest change");
+                }
+                self.restore_cursor(pos);
+            },
+            Action::UndoTo(n) => {
+                let pos = self.buffer.undo_to(n);
+                self.restore_cursor(pos);
+            },
+            Action::UndoStep(count) => {
+                let pos = self.buffer.undo_step(count);
+                self.restore_cursor(pos);
+            },
+            Action::UndoTravel(duration, forward) => {
+                let pos = self.buffer.undo_travel(duration, forward);
+                self.restore_cursor(pos);
+            },
+            Action::ShowMessages => {
+                let lines: Vec<_> = self.messages.iter().map(|m| (m.text.clone(), m.style())).collect();
+                let height = self.size.1.saturating_sub(2) as usize;
+                self.page("Messages", &lines, lines.len().saturating_sub(height))?;
+            },
+            Action::SetMark(name) => self.buffer.set_mark(name, self.cur_pos),
+            Action::EnterMode(new_mode) => {
+                if self.mode == Mode::Visual && new_mode != Mode::Visual {
+                    self.set_visual_marks();
+                }
+                if matches!(new_mode, Mode::Normal) {
+                    match self.mode {
+                        Mode::Command => self.leave_command_mode()?,
+                        Mode::Insert => {
+                            self.cur_pos.0 = self.buffer.prev_grapheme(self.cur_pos.0, self.cur_pos.1);
+                        },
+                        _ => {}
+                    }
+                };
+                self.mode = new_mode;
+            },
+        }
+        Ok(false)
+    }
 
-        Ok(action)
+    /// Brings the swap file up to date, unless swap files are turned off.
+    fn write_swap(&mut self) {
+        if self.options.updatecount == 0 {
+            return;
+        }
+        if let Err(e) = self.buffer.write_swap() {
+            self.error(format!("E303: Unable to write swap file: {e}"));
+        }
     }
 
-    fn handle_waiting_cmd(&mut self, cmd: char, ev: event::Event) -> io::Result<Option<Action>> {
-        let action = match cmd {
-            'd' => match ev {
-                event::Event::Key(event) => match event.code {
-                    event::KeyCode::Char('d') => Some(Action::DeleteCurrentLine),
-                    _ => None,
-                },
-                _ => None,
-            },
-            'g' => match ev {
-                event::Event::Key(event) => match event.code {
-                    event::KeyCode::Char('g') => Some(Action::MoveToTop),
-                    _ => None,
-                },
-                _ => None,
+    /// Asks what to do about a swap file left behind for the buffer's file,
+    /// like vim's ATTENTION prompt. Returns `false` if the user chose to
+    /// quit instead of editing.
+    pub fn check_swap(&mut self) -> io::Result<bool> {
+        let Some(path) = self.buffer.swap_file() else { return Ok(true) };
+        let found = match swap::read(path) {
+            Ok(Some(found)) => found,
+            Ok(None) => return Ok(true),
+            Err(e) => {
+                self.error(format!("E307: {} is not a usable swap file: {e}", path.display()));
+                return Ok(true);
             }
-            _ => None,
         };
 
-        Ok(action)
+        let running = found.running();
+        // Two editors can't share a swap file; this one goes without.
+        if running {
+            self.buffer.detach_swap();
+        }
+        loop {
+            self.draw_swap_prompt(&found, running)?;
+            let event::Event::Key(key) = event::read()? else { continue };
+            match key.code {
+                KeyCode::Char('o' | 'O') => {
+                    self.buffer.readonly = true;
+                    break;
+                }
+                KeyCode::Char('e' | 'E') => break,
+                KeyCode::Char('r' | 'R') => {
+                    self.buffer.recover(&found.text);
+                    self.info("Recovered from the swap file; write it to keep the changes");
+                    break;
+                }
+                KeyCode::Char('c' | 'C') => self.show_swap_diff(&found)?,
+                KeyCode::Char('d' | 'D') if !running => {
+                    if let Err(e) = std::fs::remove_file(&found.path) {
+                        self.error(format!("E306: Cannot delete {}: {e}", found.path.display()));
+                    }
+                    break;
+                }
+                KeyCode::Char('q' | 'Q') | KeyCode::Esc => return Ok(false),
+                _ => {}
+            }
+        }
+
+        self.stdout.queue(terminal::Clear(terminal::ClearType::All))?;
+        Ok(true)
     }
 
-    fn process_command(&mut self, command: String) -> Option<Action> {
-        match command.as_str() {
-            "q" => Some(Action::Quit),
-            "w" => {
-                let _ = self.buffer.save();
-                Some(Action::EnterMode(Mode::Normal))
-            },
-            "wq" => {
-                let _ = self.buffer.save();
-                Some(Action::Quit)
-            },
-            _ => Some(Action::EnterMode(Mode::Normal))
+    fn draw_swap_prompt(&mut self, found: &Found, running: bool) -> io::Result<()> {
+        let modified = found.modified.map_or_else(|| "unknown".to_string(), age);
+        let mut lines = vec![
+            format!("Found a swap file \"{}\"", found.path.display()),
+            format!(
+                "          owned by: process ID {}{}",
+                found.pid,
+                if running { " (STILL RUNNING)" } else { "" }
+            ),
+            format!("     swap modified: {modified}"),
+            format!("While opening file \"{}\"", found.file),
+            String::new(),
+        ];
+        if running {
+            lines.push("(1) Another program may be editing the same file. Take care not to".to_string());
+            lines.push("    end up with two different versions of it.".to_string());
+        } else {
+            lines.push("(1) An edit session for this file crashed. Recover to get its unsaved".to_string());
+            lines.push("    changes back, or compare them with the file first.".to_string());
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "[O]pen Read-Only, (E)dit anyway, (R)ecover, (C)ompare{}, (Q)uit:",
+            if running { "" } else { ", (D)elete it" }
+        ));
+
+        self.stdout
+            .queue(terminal::Clear(terminal::ClearType::All))?
+            .queue(cursor::MoveTo(0, 0))?
+            .queue(style::PrintStyledContent("E325: ATTENTION".with(Color::Red).bold()))?;
+        for (i, line) in lines.iter().enumerate() {
+            self.stdout.queue(cursor::MoveTo(0, i as u16 + 1))?.queue(style::Print(line))?;
         }
+        self.stdout.flush()
     }
 
-    pub fn cleanup(&mut self) -> io::Result<()> {
-        self.stdout.execute(LeaveAlternateScreen)?;
-        disable_raw_mode()?;
+    /// Shows how the swap file differs from the file.
+    fn show_swap_diff(&mut self, found: &Found) -> io::Result<()> {
+        let mut lines: Vec<_> = swap::diff(&self.buffer.contents(), &found.text)
+            .into_iter()
+            .map(|line| {
+                let color = match line.chars().next() {
+                    Some('-') => Color::Red,
+                    Some('+') => Color::Green,
+                    _ => Color::Cyan,
+                };
+                (line, style::ContentStyle::new().with(color))
+            })
+            .collect();
+        if lines.is_empty() {
+            lines.push(("The swap file has the same text as the file.".to_string(), style::ContentStyle::new()));
+        }
+        self.page(&format!("--- \"{}\"   +++ swap file", found.file), &lines, 0)
+    }
+
+    /// Shows `lines` full screen, starting at line `top`, until a key is
+    /// pressed that isn't for scrolling.
+    fn page(&mut self, title: &str, lines: &[(String, style::ContentStyle)], mut top: usize) -> io::Result<()> {
+        let width = self.size.0 as usize;
+        let height = self.size.1.saturating_sub(2) as usize;
+        let last = lines.len().saturating_sub(height);
+        loop {
+            self.stdout
+                .queue(terminal::Clear(terminal::ClearType::All))?
+                .queue(cursor::MoveTo(0, 0))?
+                .queue(style::PrintStyledContent(title.bold()))?;
+            for (i, (line, style)) in lines.iter().skip(top).take(height).enumerate() {
+                let line: String = line.chars().take(width).collect();
+                self.stdout
+                    .queue(cursor::MoveTo(0, i as u16 + 1))?
+                    .queue(style::PrintStyledContent(style.apply(line)))?;
+            }
+            self.stdout
+                .queue(cursor::MoveTo(0, self.size.1 - 1))?
+                .queue(style::PrintStyledContent("j/k to scroll, any other key to go back".dark_grey()))?
+                .flush()?;
+
+            let event::Event::Key(key) = event::read()? else { continue };
+            top = match key.code {
+                KeyCode::Char('j') | KeyCode::Down => top + 1,
+                KeyCode::Char('k') | KeyCode::Up => top.saturating_sub(1),
+                KeyCode::Char(' ') | KeyCode::PageDown => top + height,
+                KeyCode::Char('b') | KeyCode::PageUp => top.saturating_sub(height),
+                KeyCode::Char('g') | KeyCode::Home => 0,
+                KeyCode::Char('G') | KeyCode::End => last,
+                _ => break,
+            }
+            .min(last);
+        }
+        self.stdout.queue(terminal::Clear(terminal::ClearType::All))?;
+        Ok(())
+    }
+
+    /// What typing Tab inserts, following `expandtab` and `softtabstop`.
+    /// Returns the column to insert at: without `expandtab`, spaces right
+    /// before the cursor are replaced so they can become part of a tab.
+    fn tab_text(&self) -> (usize, String) {
+        let sts = self.options.softtabstop();
+        if sts == 0 && !self.options.expandtab {
+            return (self.cur_pos.0, "\t".to_string());
+        }
+
+        let ts = self.options.tabstop;
+        let col = self.cursor_col();
+        let stop = if sts > 0 { sts } else { ts };
+        let target = (col / stop + 1) * stop;
+        if self.options.expandtab {
+            return (self.cur_pos.0, " ".repeat(target - col));
+        }
+
+        let line = self.cur_pos.1;
+        let mut start = self.cur_pos.0;
+        while start > 0 && self.buffer.char_at(start - 1, line) == Some(' ') {
+            start -= 1;
+        }
+
+        let mut text = String::new();
+        let mut col = self.buffer.display_col(start, line, ts);
+        while (col / ts + 1) * ts <= target {
+            text.push('\t');
+            col = (col / ts + 1) * ts;
+        }
+        (start, text + &" ".repeat(target - col))
+    }
+
+    /// With `softtabstop` set, Backspace after whitespace deletes back to
+    /// the previous soft tab stop. Returns whether it did.
+    fn delete_soft_tab(&mut self) -> bool {
+        let sts = self.options.softtabstop();
+        let line = self.cur_pos.1;
+        let is_blank = |c: Option<char>| matches!(c, Some(' ' | '\t'));
+        if sts == 0 || self.cur_pos.0 == 0 || !is_blank(self.buffer.char_at(self.cur_pos.0 - 1, line)) {
+            return false;
+        }
+
+        let mut col = self.cursor_col();
+        let target = (col - 1) / sts * sts;
+        while col > target && self.cur_pos.0 > 0 && is_blank(self.buffer.char_at(self.cur_pos.0 - 1, line)) {
+            self.cur_pos.0 -= 1;
+            self.buffer.delete(self.cur_pos.0, line);
+            col = self.cursor_col();
+        }
+        // Deleting a tab may have gone past the stop.
+        if col < target {
+            self.buffer.insert_str(self.cur_pos.0, line, &" ".repeat(target - col));
+            self.cur_pos.0 += target - col;
+        }
+        true
+    }
+
+    /// Where `motion` moves the cursor, repeated or otherwise changed by
+    /// `count`, and how it makes a range for an operator. `None` if it
+    /// can't move, as `k` on the first line.
+    fn motion_target(&mut self, motion: Motion, count: Option<usize>) -> Option<((usize, usize), MotionKind)> {
+        let n = count.unwrap_or(1).max(1);
+        let (mut x, y) = self.cur_pos;
+        let last = self.buffer.len() - 1;
+        let col = self.cursor_col();
+        let line_at = |y| (self.buffer.col_to_char(col, y, self.options.tabstop), y);
+
+        let target = match motion {
+            Motion::Up if y > 0 => (line_at(y.saturating_sub(n)), MotionKind::Linewise),
+            Motion::Down if y < last => (line_at((y + n).min(last)), MotionKind::Linewise),
+            Motion::Left if x > 0 => {
+                for _ in 0..n {
+                    x = self.buffer.prev_grapheme(x, y);
+                }
+                ((x, y), MotionKind::Exclusive)
+            }
+            Motion::Right if x < self.buffer.line_len(y) => {
+                for _ in 0..n {
+                    x = self.buffer.next_grapheme(x, y);
+                }
+                ((x, y), MotionKind::Exclusive)
+            }
+            Motion::Up | Motion::Down | Motion::Left | Motion::Right => return None,
+            Motion::ScreenRowUp | Motion::ScreenRowDown => {
+                let mut pos = self.cur_pos;
+                for _ in 0..n {
+                    let Some(next) = self.screen_row_target(pos, motion == Motion::ScreenRowDown) else { break };
+                    pos = next;
+                }
+                if pos == self.cur_pos {
+                    return None;
+                }
+                (pos, MotionKind::Exclusive)
+            }
+            Motion::WordForward(big) => {
+                let text = self.buffer.text();
+                let pos = word::forward(text, self.buffer.char_idx(x, y), n, big, false);
+                let (x, y) = self.buffer.char_to_pos(pos);
+                // Out of words: stop on the last character.
+                let x = if pos == text.len_chars() { self.buffer.prev_grapheme(x, y) } else { x };
+                ((x, y), MotionKind::Exclusive)
+            }
+            Motion::WordBackward(big) => {
+                let pos = word::backward(self.buffer.text(), self.buffer.char_idx(x, y), n, big);
+                (self.buffer.char_to_pos(pos), MotionKind::Exclusive)
+            }
+            Motion::WordEnd(big) => {
+                let pos = word::end(self.buffer.text(), self.buffer.char_idx(x, y), n, big, false);
+                (self.grapheme_at(pos), MotionKind::Inclusive)
+            }
+            Motion::WordEndBackward(big) => {
+                let pos = word::backward_end(self.buffer.text(), self.buffer.char_idx(x, y), n, big);
+                (self.grapheme_at(pos), MotionKind::Inclusive)
+            }
+            Motion::LineStart => ((0, y), MotionKind::Exclusive),
+            Motion::FirstNonBlank => ((self.buffer.first_non_blank(y), y), MotionKind::Exclusive),
+            Motion::LineEnd => {
+                let y = (y + n - 1).min(last);
+                let end = self.buffer.prev_grapheme(self.buffer.line_len(y), y);
+                ((end, y), MotionKind::Inclusive)
+            }
+            Motion::Find(find) => self.find_target(find, n, false)?,
+            Motion::RepeatFind(reverse) => {
+                let mut find = self.last_find?;
+                find.forward ^= reverse;
+                self.find_target(find, n, true)?
+            }
+            Motion::SearchNext(reverse) => (self.search_next(self.cur_pos, reverse, n)?, MotionKind::Exclusive),
+            Motion::SearchWord(forward) => {
+                let Some((start, pattern)) = search::word_pattern(&self.buffer, self.cur_pos) else {
+                    self.error("E348: No string under cursor");
+                    return None;
+                };
+                self.last_search = Some(Search { pattern, forward });
+                (self.search_next((start, y), false, n)?, MotionKind::Exclusive)
+            }
+            Motion::Mark(name, linewise) => {
+                let Some((x, y)) = self.buffer.mark(name) else {
+                    self.error("E20: Mark not set");
+                    return None;
+                };
+                let y = y.min(last);
+                match linewise {
+                    true => ((self.buffer.first_non_blank(y), y), MotionKind::Linewise),
+                    false => ((x.min(self.buffer.line_len(y)), y), MotionKind::Exclusive),
+                }
+            }
+            Motion::FirstLine | Motion::LastLine => {
+                let default = if motion == Motion::FirstLine { 0 } else { last };
+                let y = count.map_or(default, |n| n.saturating_sub(1).min(last));
+                ((self.buffer.first_non_blank(y), y), MotionKind::Linewise)
+            }
+            Motion::Screen(row) => {
+                let (first, end) = self.cursor_bounds();
+                let y = match row {
+                    ScreenRow::Top => (first + n - 1).min(end),
+                    ScreenRow::Middle => self.vtop + (self.bottom_line() - self.vtop) / 2,
+                    ScreenRow::Bottom => end.saturating_sub(n - 1).max(first),
+                };
+                ((self.buffer.first_non_blank(y), y), MotionKind::Linewise)
+            }
+            Motion::Lines => ((x, (y + n - 1).min(last)), MotionKind::Linewise),
+            Motion::Object(..) => return None,
+        };
+        (target.0 != self.cur_pos || target.1 == MotionKind::Linewise).then_some(target)
+    }
+
+    /// Where the `count`th `find.target` from the cursor is on its line.
+    /// Going forward includes the character, going back doesn't. A repeated
+    /// `t` or `T` skips a match right next to the cursor, so that `;` gets
+    /// past the one it stopped at.
+    fn find_target(&self, find: Find, count: usize, repeat: bool) -> Option<((usize, usize), MotionKind)> {
+        let (x, y) = self.cur_pos;
+        let line: Vec<char> = self.buffer.get(y)?.chars().take(self.buffer.line_len(y)).collect();
+        let mut i = x;
+        for k in 0..count {
+            loop {
+                i = if find.forward { i + 1 } else { i.checked_sub(1)? };
+                let adjacent = i.abs_diff(x) == 1;
+                if *line.get(i)? == find.target && !(find.till && repeat && k == 0 && adjacent) {
+                    break;
+                }
+            }
+        }
+        Some(match (find.forward, find.till) {
+            (true, false) => ((i, y), MotionKind::Inclusive),
+            (true, true) => ((self.buffer.prev_grapheme(i, y), y), MotionKind::Inclusive),
+            (false, false) => ((i, y), MotionKind::Exclusive),
+            (false, true) => ((self.buffer.next_grapheme(i, y), y), MotionKind::Exclusive),
+        })
+    }
+
+    /// Where the `count`th match of the last search is from `from`, going
+    /// the way it went, or the other way with `reverse` as for `N`. Says
+    /// so if the search wrapped around the buffer or found nothing.
+    fn search_next(&mut self, from: (usize, usize), reverse: bool, count: usize) -> Option<(usize, usize)> {
+        let Some(search) = self.last_search.clone() else {
+            self.error("E35: No previous regular expression");
+            return None;
+        };
+        let Ok(regex) = self.search_regex(&search.pattern) else {
+            self.error(format!("E383: Invalid search string: {}", search.pattern));
+            return None;
+        };
+        let forward = search.forward != reverse;
+        let (mut pos, mut wrapped) = (from, false);
+        for _ in 0..count {
+            let Some((found, wrap)) = search::find(&self.buffer, &regex, pos, forward, self.options.wrapscan) else {
+                self.error(match (self.options.wrapscan, forward) {
+                    (true, _) => format!("E486: Pattern not found: {}", search.pattern),
+                    (false, true) => format!("E385: Search hit BOTTOM without match for: {}", search.pattern),
+                    (false, false) => format!("E384: Search hit TOP without match for: {}", search.pattern),
+                });
+                return None;
+            };
+            pos = found.start;
+            wrapped |= wrap;
+        }
+        self.nohlsearch = false;
+        let count = search::count(&self.buffer, &regex, pos);
+        if wrapped {
+            let text = if forward { "search hit BOTTOM, continuing at TOP" } else { "search hit TOP, continuing at BOTTOM" };
+            self.warn(self.with_count(text, &count));
+        } else {
+            // Echoed the way it was typed, but not kept for `:messages`.
+            let prompt = if forward { '/' } else { '?' };
+            let text = self.with_count(&format!("{prompt}{}", search.pattern), &count);
+            self.message = Some(Message { level: Level::Info, text });
+        }
+        Some(pos)
+    }
+
+    /// `text` with a search count such as `[3/17]` at the right end of the
+    /// command line.
+    fn with_count(&self, text: &str, count: &str) -> String {
+        let width = (self.size.0 as usize).saturating_sub(1);
+        let padding = width.saturating_sub(text.width() + count.width()).max(1);
+        format!("{text}{:padding$}{count}", "")
+    }
+
+    fn search_regex(&self, pattern: &str) -> Result<regex::Regex, regex::Error> {
+        search::compile(pattern, self.options.ignorecase, self.options.smartcase)
+    }
+
+    /// Position of the grapheme that char index `pos` is part of.
+    fn grapheme_at(&self, pos: usize) -> (usize, usize) {
+        let (x, y) = self.buffer.char_to_pos(pos);
+        (self.buffer.prev_grapheme(self.buffer.next_grapheme(x, y), y), y)
+    }
+
+    /// The text `op` works on between the cursor and where `motion` goes.
+    fn motion_range(&mut self, op: Operator, motion: Motion, count: Option<usize>) -> Option<Range> {
+        let n = count.unwrap_or(1).max(1);
+        let (x, y) = self.cur_pos;
+        let text = self.buffer.text();
+        let on_blank = self.buffer.char_at(x, y).is_none_or(char::is_whitespace);
+        let (target, kind) = match motion {
+            Motion::Object(object, inner) => {
+                return textobject::select(&self.buffer, object, inner, self.cur_pos, n);
+            }
+            // `cw` on a word changes up to the end of it, like `ce`.
+            Motion::WordForward(big) if op == Operator::Change && !on_blank => {
+                let pos = word::end(text, self.buffer.char_idx(x, y), n, big, true);
+                (self.grapheme_at(pos), MotionKind::Inclusive)
+            }
+            // The last word stops at the end of its line rather than taking
+            // the line break and indent after it.
+            Motion::WordForward(big) => {
+                let pos = word::forward(text, self.buffer.char_idx(x, y), n, big, true);
+                (self.buffer.char_to_pos(pos), MotionKind::Exclusive)
+            }
+            _ => self.motion_target(motion, count)?,
+        };
+        let (start, end) = ordered(self.cur_pos, target);
+        Some(match kind {
+            MotionKind::Linewise => Range { start, end, linewise: true },
+            MotionKind::Inclusive => {
+                let end = (self.buffer.next_grapheme(end.0, end.1), end.1);
+                Range { start, end, linewise: false }
+            }
+            // Ending at the start of a line, it stops at the end of the one
+            // before instead, and takes whole lines if it started before
+            // any text on its first line (`:help exclusive`).
+            MotionKind::Exclusive if end.0 == 0 && end.1 > start.1 => {
+                let end = (self.buffer.line_len(end.1 - 1), end.1 - 1);
+                Range { start, end, linewise: start.0 <= self.buffer.first_non_blank(start.1) }
+            }
+            MotionKind::Exclusive => Range { start, end, linewise: false },
+        })
+    }
+
+    /// Remembers the Visual selection as marks `<` and `>`, for `:'<,'>`.
+    fn set_visual_marks(&mut self) {
+        let (start, end) = ordered(self.visual_start, self.cur_pos);
+        self.buffer.set_mark('<', start);
+        self.buffer.set_mark('>', end);
+    }
+
+    /// The text selected in Visual mode.
+    fn visual_range(&self) -> Range {
+        let (start, end) = ordered(self.visual_start, self.cur_pos);
+        let end = if self.visual_linewise { end } else { (self.buffer.next_grapheme(end.0, end.1), end.1) };
+        Range { start, end, linewise: self.visual_linewise }
+    }
+
+    /// Selects a text object in Visual mode. Selecting the one that is
+    /// already selected takes in the next one, or the brackets or tags
+    /// around it.
+    fn select_object(&mut self, object: TextObject, inner: bool, count: Option<usize>) {
+        let (current, grow) = (self.visual_range(), self.visual_start != self.cur_pos);
+        let mut count = count.unwrap_or(1);
+        let mut previous = None;
+        let range = loop {
+            let Some(range) = textobject::select(&self.buffer, object, inner, self.cur_pos, count) else { return };
+            if !grow || range != current {
+                break range;
+            }
+            if previous == Some(range) {
+                return;
+            }
+            previous = Some(range);
+            count += 1;
+        };
+        if !range.linewise && range.start == range.end {
+            return;
+        }
+
+        self.visual_linewise = range.linewise;
+        self.visual_start = range.start;
+        let (x, y) = range.end;
+        self.cur_pos = match (range.linewise, x) {
+            (true, _) => (0, y),
+            // Up to a line break: the selection ends at the end of the line.
+            (false, 0) if y > range.start.1 => (self.buffer.line_len(y - 1), y - 1),
+            (false, _) => (self.buffer.prev_grapheme(x, y), y),
+        };
+        if range.linewise {
+            self.visual_start.0 = 0;
+        }
+    }
+
+    /// Applies `op` to the text in `range`.
+    fn operate(&mut self, op: Operator, range: Range) {
+        let lines = range.line_count();
+        if matches!(op, Operator::Delete | Operator::Change | Operator::Yank) {
+            self.register = Register { text: self.buffer.text_in(&range), linewise: range.linewise };
+        }
+
+        match op {
+            Operator::Yank => {
+                self.cur_pos = range.start;
+                if range.linewise && lines > REPORT {
+                    self.info(format!("{lines} lines yanked"));
+                }
+            }
+            Operator::Delete => {
+                self.buffer.delete_range(&range);
+                if range.linewise {
+                    self.move_to_line_start(range.start.1.min(self.buffer.len() - 1));
+                    if lines > REPORT {
+                        self.info(format!("{lines} fewer lines"));
+                    }
+                } else {
+                    let (x, y) = range.start;
+                    // Stay on the last character, as after `D`.
+                    let len = self.buffer.line_len(y);
+                    self.cur_pos = (if x < len { x } else { self.buffer.prev_grapheme(len, y) }, y);
+                }
+            }
+            Operator::Change => {
+                if range.linewise {
+                    self.buffer.replace_range(&range, "");
+                    self.cur_pos = (0, range.start.1);
+                } else {
+                    self.buffer.delete_range(&range);
+                    self.cur_pos = range.start;
+                }
+                self.mode = Mode::Insert;
+            }
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                self.shift(range.lines(), op == Operator::ShiftLeft, 1);
+                self.move_to_line_start(range.start.1);
+                if lines > REPORT {
+                    self.info(format!("{lines} lines {}ed 1 time", op.key()));
+                }
+            }
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+                let text = self.buffer.text_in(&range);
+                let text = if range.linewise { &text[..text.len() - 1] } else { &text };
+                self.buffer.replace_range(&range, &op.convert_case(text));
+                self.cur_pos = range.start;
+                if lines > REPORT {
+                    self.info(format!("{lines} lines changed"));
+                }
+            }
+            Operator::Indent => {
+                self.reindent(range.lines());
+                self.move_to_line_start(range.start.1);
+                if lines > REPORT {
+                    self.info(format!("{lines} lines indented "));
+                }
+            }
+        }
+    }
+
+    /// `p` and `P`: puts the register after or before the cursor, or the
+    /// cursor line if it holds lines, `count` times.
+    fn put(&mut self, before: bool, count: usize) {
+        if self.register.text.is_empty() {
+            self.error("E353: Nothing in register \"");
+            return;
+        }
+        let text = self.register.text.repeat(count);
+        let (x, y) = self.cur_pos;
+        if self.register.linewise {
+            let line = if before { y } else { y + 1 };
+            self.buffer.insert_line(line, &text[..text.len() - 1]);
+            self.move_to_line_start(line);
+            let lines = text.lines().count();
+            if lines > REPORT {
+                self.info(format!("{lines} more lines"));
+            }
+            return;
+        }
+
+        let x = if before { x } else { self.buffer.next_grapheme(x, y) };
+        self.buffer.insert_str(x, y, &text);
+        // The cursor ends up on the last character put.
+        let end = match text.rsplit_once('\n') {
+            Some((head, tail)) => (tail.chars().count(), y + head.matches('\n').count() + 1),
+            None => (x + text.chars().count(), y),
+        };
+        self.cur_pos = (self.buffer.prev_grapheme(end.0, end.1), end.1);
+    }
+
+    /// `=`: indents `lines` by the brackets they open and close, carrying
+    /// on from the nearest line with text above them.
+    fn reindent(&mut self, lines: RangeInclusive<usize>) {
+        let sw = self.options.shiftwidth() as isize;
+        let next_width = |width: usize, line: &str| (width as isize + operator::indent_change(line) * sw).max(0) as usize;
+        let above = (0..*lines.start()).rev().find(|&y| self.leading_blanks(y) < self.buffer.line_len(y));
+        let mut width = above.map_or(0, |y| {
+            let line = self.buffer.get(y).map(|l| l.to_string()).unwrap_or_default();
+            next_width(self.indent_width(y), &line)
+        });
+
+        for y in lines {
+            let line = self.buffer.get(y).map(|l| l.to_string()).unwrap_or_default();
+            if line.trim().is_empty() {
+                self.set_indent(y, 0);
+                continue;
+            }
+            let indent = if operator::starts_with_closer(&line) { width.saturating_sub(sw as usize) } else { width };
+            self.set_indent(y, indent);
+            width = next_width(indent, &line);
+        }
+    }
+
+    /// Shifts the indent of `lines` by `times` shiftwidths, to the left if
+    /// `left` is set. Empty lines aren't indented.
+    fn shift(&mut self, lines: RangeInclusive<usize>, left: bool, times: usize) {
+        let sw = self.options.shiftwidth() * times;
+        for y in lines {
+            let width = self.indent_width(y);
+            if left {
+                self.set_indent(y, width.saturating_sub(sw));
+            } else if self.buffer.line_len(y) > 0 {
+                self.set_indent(y, width + sw);
+            }
+        }
+    }
+
+    /// Number of spaces and tabs at the start of line `y`.
+    fn leading_blanks(&self, y: usize) -> usize {
+        self.buffer.get(y).map_or(0, |line| line.chars().take_while(|c| matches!(c, ' ' | '\t')).count())
+    }
+
+    /// Screen columns taken up by the indent of line `y`.
+    fn indent_width(&self, y: usize) -> usize {
+        self.buffer.display_col(self.leading_blanks(y), y, self.options.tabstop)
+    }
+
+    /// Replaces the indent of line `y` with one `width` columns wide.
+    fn set_indent(&mut self, y: usize, width: usize) {
+        let range = Range { start: (0, y), end: (self.leading_blanks(y), y), linewise: false };
+        self.buffer.replace_range(&range, &self.options.indent(width));
+    }
+
+    fn restore_cursor(&mut self, pos: Option<(usize, usize)>) {
+        if let Some((x, y)) = pos {
+            self.cur_pos.1 = y.min(self.buffer.len() - 1);
+            self.cur_pos.0 = x.min(self.buffer.line_len(self.cur_pos.1));
+        }
+    }
+
+    fn leave_command_mode(&mut self) -> io::Result<()> {
+        self.command_buffer.clear();
+        self.clear_command()?;
+        self.mode = Mode::Normal;
+        Ok(())
+    }
+
+    fn handle_event(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        if matches!(ev, event::Event::Resize(_, _)) {
+            self.size = terminal::size()?;
+        }
+        if matches!(ev, event::Event::Key(_)) {
+            self.message = None;
+        }
+        self.dispatch(ev)
+    }
+
+    /// Hands an event to the mode it is for.
+    fn dispatch(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        match self.mode {
+            Mode::Normal => self.handle_normal_mode(ev),
+            Mode::Insert => self.handle_insert_mode(ev),
+            Mode::Visual => self.handle_visual_mode(ev),
+            Mode::Command => self.handle_command_mode(ev),
+            Mode::Search => self.handle_search_mode(ev),
+        }
+    }
+
+    // Normal Mode
+    /// Reads Normal mode commands a key at a time: an optional count, then
+    /// a motion or a command, or an operator followed by another optional
+    /// count and a motion, as in `3d2w`.
+    fn handle_normal_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        let event::Event::Key(event) = ev else { return Ok(None) };
+        let mut pending = std::mem::take(&mut self.pending);
+        let key = match pending.prefix.take() {
+            Some(prefix) => self.prefixed_key(prefix, event.code),
+            None => self.normal_key(event, &pending),
+        };
+
+        let action = match key {
+            Some(NormalKey::Digit(digit)) => {
+                pending.count = Some(pending.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                self.pending = pending;
+                None
+            }
+            Some(NormalKey::Prefix(prefix))
+                if matches!(prefix, 'g' | 'i' | 'a' | 'f' | 'F' | 't' | 'T' | '\'' | '`') || pending.operator.is_none() =>
+            {
+                pending.prefix = Some(prefix);
+                self.pending = pending;
+                None
+            }
+            Some(NormalKey::Operator(op)) if self.mode == Mode::Visual => Some(Action::OperateVisual(op, false)),
+            Some(NormalKey::Motion(Motion::Object(object, inner))) if self.mode == Mode::Visual => {
+                Some(Action::SelectObject(object, inner, pending.count))
+            }
+            Some(NormalKey::Operator(op)) => match pending.operator {
+                None => {
+                    pending.operator = Some((op, pending.count.take()));
+                    self.pending = pending;
+                    None
+                }
+                // Doubled, as in `dd` or `gUgU`, it works on lines.
+                Some((first, count)) if first == op => {
+                    Some(Action::Operate(op, Motion::Lines, multiply(count, pending.count)))
+                }
+                Some(_) => None,
+            },
+            Some(NormalKey::Motion(motion)) => match pending.operator {
+                Some((op, count)) => Some(Action::Operate(op, motion, multiply(count, pending.count))),
+                None => Some(Action::Move(motion, pending.count)),
+            },
+            Some(NormalKey::Search(forward)) => {
+                let (operator, count) = match pending.operator {
+                    Some((op, count)) => (Some(op), multiply(count, pending.count)),
+                    None => (None, pending.count),
+                };
+                let (cursor, vtop, vleft) = (self.cur_pos, self.vtop, self.vleft);
+                let visual = self.mode == Mode::Visual;
+                self.search_start = Some(SearchStart { forward, count, operator, visual, cursor, vtop, vleft });
+                Some(Action::EnterMode(Mode::Search))
+            }
+            // Anything else cancels a pending operator.
+            _ if pending.operator.is_some() => None,
+            Some(NormalKey::Action(action)) if self.mode == Mode::Visual => match action {
+                Action::ScrollLines(_)
+                | Action::ScrollHalfPage(_)
+                | Action::ScrollPage(_)
+                | Action::ScrollCursorTo(_)
+                | Action::Visual(_) => Some(action),
+                _ => None,
+            },
+            _ if self.mode == Mode::Visual => None,
+            Some(NormalKey::Shorthand(op, motion)) => Some(Action::Operate(op, motion, pending.count)),
+            Some(NormalKey::Put(before)) => Some(Action::Put { before, count: pending.count.unwrap_or(1) }),
+            Some(NormalKey::Action(action)) => Some(action),
+            _ => None,
+        };
+
+        Ok(action)
+    }
+
+    fn normal_key(&mut self, event: event::KeyEvent, pending: &Pending) -> Option<NormalKey> {
+        let code = event.code;
+        let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+        if let (Some((op, _)), KeyCode::Char(c)) = (pending.operator, code) {
+            if c == op.key() && !ctrl {
+                return Some(NormalKey::Operator(op));
+            }
+        }
+
+        Some(match code {
+            KeyCode::Char(c) if ctrl => NormalKey::Action(match c {
+                'r' => Action::Redo,
+                'e' => Action::ScrollLines(1),
+                'y' => Action::ScrollLines(-1),
+                'd' => Action::ScrollHalfPage(1),
+                'u' => Action::ScrollHalfPage(-1),
+                'f' => Action::ScrollPage(1),
+                'b' => Action::ScrollPage(-1),
+                _ => return None,
+            }),
+            KeyCode::Char(c @ '1'..='9') => NormalKey::Digit(c as usize - '0' as usize),
+            KeyCode::Char('0') if pending.count.is_some() => NormalKey::Digit(0),
+            KeyCode::PageDown => NormalKey::Action(Action::ScrollPage(1)),
+            KeyCode::PageUp => NormalKey::Action(Action::ScrollPage(-1)),
+            KeyCode::Char('H') => NormalKey::Motion(Motion::Screen(ScreenRow::Top)),
+            KeyCode::Char('M') => NormalKey::Motion(Motion::Screen(ScreenRow::Middle)),
+            KeyCode::Char('L') => NormalKey::Motion(Motion::Screen(ScreenRow::Bottom)),
+            KeyCode::Char(c @ ('g' | 'z' | 'Z' | 'f' | 'F' | 't' | 'T' | 'm' | '\'' | '`')) => NormalKey::Prefix(c),
+            KeyCode::Char(c @ ('i' | 'a')) if pending.operator.is_some() || self.mode == Mode::Visual => {
+                NormalKey::Prefix(c)
+            }
+            KeyCode::Char('q') if pending.operator.is_none() && self.mode == Mode::Normal => {
+                NormalKey::Action(Action::Exit { write: false, force: false })
+            }
+            KeyCode::Char('u') => NormalKey::Action(Action::Undo),
+            KeyCode::Up | KeyCode::Char('k') => NormalKey::Motion(Motion::Up),
+            KeyCode::Down | KeyCode::Char('j') => NormalKey::Motion(Motion::Down),
+            KeyCode::Right | KeyCode::Char('l') => NormalKey::Motion(Motion::Right),
+            KeyCode::Left | KeyCode::Char('h') => NormalKey::Motion(Motion::Left),
+            KeyCode::Char('w') => NormalKey::Motion(Motion::WordForward(false)),
+            KeyCode::Char('W') => NormalKey::Motion(Motion::WordForward(true)),
+            KeyCode::Char('b') => NormalKey::Motion(Motion::WordBackward(false)),
+            KeyCode::Char('B') => NormalKey::Motion(Motion::WordBackward(true)),
+            KeyCode::Char('e') => NormalKey::Motion(Motion::WordEnd(false)),
+            KeyCode::Char('E') => NormalKey::Motion(Motion::WordEnd(true)),
+            KeyCode::Char('0') => NormalKey::Motion(Motion::LineStart),
+            KeyCode::Char('^') => NormalKey::Motion(Motion::FirstNonBlank),
+            KeyCode::Char('$') => NormalKey::Motion(Motion::LineEnd),
+            KeyCode::Char('/') => NormalKey::Search(true),
+            KeyCode::Char('?') => NormalKey::Search(false),
+            KeyCode::Char('n') => NormalKey::Motion(Motion::SearchNext(false)),
+            KeyCode::Char('N') => NormalKey::Motion(Motion::SearchNext(true)),
+            KeyCode::Char('*') => NormalKey::Motion(Motion::SearchWord(true)),
+            KeyCode::Char('#') => NormalKey::Motion(Motion::SearchWord(false)),
+            KeyCode::Char(';') => NormalKey::Motion(Motion::RepeatFind(false)),
+            KeyCode::Char(',') => NormalKey::Motion(Motion::RepeatFind(true)),
+            KeyCode::Char('G') => NormalKey::Motion(Motion::LastLine),
+            KeyCode::Char('x') => NormalKey::Shorthand(Operator::Delete, Motion::Right),
+            KeyCode::Char('X') => NormalKey::Shorthand(Operator::Delete, Motion::Left),
+            KeyCode::Char('D') => NormalKey::Shorthand(Operator::Delete, Motion::LineEnd),
+            KeyCode::Char('C') => NormalKey::Shorthand(Operator::Change, Motion::LineEnd),
+            KeyCode::Char('s') => NormalKey::Shorthand(Operator::Change, Motion::Right),
+            KeyCode::Char('S') => NormalKey::Shorthand(Operator::Change, Motion::Lines),
+            KeyCode::Char('Y') => NormalKey::Shorthand(Operator::Yank, Motion::Lines),
+            KeyCode::Char('p') => NormalKey::Put(false),
+            KeyCode::Char('P') => NormalKey::Put(true),
+            KeyCode::Char('O') => NormalKey::Action(Action::OpenLineAbove),
+            KeyCode::Char('o') => NormalKey::Action(Action::OpenLineBelow),
+            KeyCode::Char('i') => NormalKey::Action(Action::EnterMode(Mode::Insert)),
+            KeyCode::Char('a') => {
+                self.cur_pos.0 = self.buffer.next_grapheme(self.cur_pos.0, self.cur_pos.1);
+                NormalKey::Action(Action::EnterMode(Mode::Insert))
+            },
+            KeyCode::Char('A') => {
+                self.cur_pos.0 = self.buffer.line_len(self.cur_pos.1);
+                NormalKey::Action(Action::EnterMode(Mode::Insert))
+            },
+            KeyCode::Char('I') => {
+                self.cur_pos.0 = self.buffer.first_non_blank(self.cur_pos.1);
+                NormalKey::Action(Action::EnterMode(Mode::Insert))
+            },
+            KeyCode::Char('v') => NormalKey::Action(Action::Visual(false)),
+            KeyCode::Char('V') => NormalKey::Action(Action::Visual(true)),
+            KeyCode::Char(':') => NormalKey::Action(Action::EnterMode(Mode::Command)),
+            KeyCode::Char(c) => NormalKey::Operator(Operator::from_key(false, c)?),
+            _ => return None,
+        })
+    }
+
+    /// The second key of a command starting with `g`, `z` or `Z`, a text
+    /// object, the character for `f`, `F`, `t` or `T`, or a mark name.
+    fn prefixed_key(&mut self, prefix: char, code: KeyCode) -> Option<NormalKey> {
+        let c = match code {
+            KeyCode::Char(c) => c,
+            KeyCode::Tab => '\t',
+            _ => return None,
+        };
+        Some(match (prefix, c) {
+            ('f' | 'F' | 't' | 'T', target) => {
+                let find = Find { target, forward: prefix.is_lowercase(), till: matches!(prefix, 't' | 'T') };
+                self.last_find = Some(find);
+                NormalKey::Motion(Motion::Find(find))
+            }
+            ('i' | 'a', c) => NormalKey::Motion(Motion::Object(TextObject::from_key(c)?, prefix == 'i')),
+            ('m', c) if c.is_ascii_lowercase() => NormalKey::Action(Action::SetMark(c)),
+            ('\'' | '`', c) if c.is_ascii_lowercase() || matches!(c, '<' | '>') => {
+                NormalKey::Motion(Motion::Mark(c, prefix == '\''))
+            }
+            ('g', 'g') => NormalKey::Motion(Motion::FirstLine),
+            ('g', 'e') => NormalKey::Motion(Motion::WordEndBackward(false)),
+            ('g', 'E') => NormalKey::Motion(Motion::WordEndBackward(true)),
+            ('g', 'j') => NormalKey::Motion(Motion::ScreenRowDown),
+            ('g', 'k') => NormalKey::Motion(Motion::ScreenRowUp),
+            ('g', '-') => NormalKey::Action(Action::UndoStep(-1)),
+            ('g', '+') => NormalKey::Action(Action::UndoStep(1)),
+            ('g', c) => NormalKey::Operator(Operator::from_key(true, c)?),
+            ('z', 't') => NormalKey::Action(Action::ScrollCursorTo(ScreenRow::Top)),
+            ('z', 'z') => NormalKey::Action(Action::ScrollCursorTo(ScreenRow::Middle)),
+            ('z', 'b') => NormalKey::Action(Action::ScrollCursorTo(ScreenRow::Bottom)),
+            ('Z', 'Z') => NormalKey::Action(Action::Exit { write: true, force: false }),
+            ('Z', 'Q') => NormalKey::Action(Action::Exit { write: false, force: true }),
+            _ => return None,
+        })
+    }
+
+    /// Visual mode takes the same motions and counts as Normal mode. An
+    /// operator applies to the selection straight away.
+    fn handle_visual_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        let event::Event::Key(event) = ev else { return Ok(None) };
+        let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+        if self.pending.prefix.is_none() {
+            let action = match event.code {
+                KeyCode::Esc => Some(Action::EnterMode(Mode::Normal)),
+                KeyCode::Char('c') if ctrl => Some(Action::EnterMode(Mode::Normal)),
+                KeyCode::Char(_) if ctrl => None,
+                KeyCode::Char('o') => Some(Action::SwapVisualEnds),
+                KeyCode::Char('x') => Some(Action::OperateVisual(Operator::Delete, false)),
+                KeyCode::Char('X' | 'D') => Some(Action::OperateVisual(Operator::Delete, true)),
+                KeyCode::Char('s') => Some(Action::OperateVisual(Operator::Change, false)),
+                KeyCode::Char('S' | 'C' | 'R') => Some(Action::OperateVisual(Operator::Change, true)),
+                KeyCode::Char('Y') => Some(Action::OperateVisual(Operator::Yank, true)),
+                KeyCode::Char('u') => Some(Action::OperateVisual(Operator::Lowercase, false)),
+                KeyCode::Char('U') => Some(Action::OperateVisual(Operator::Uppercase, false)),
+                KeyCode::Char('~') => Some(Action::OperateVisual(Operator::ToggleCase, false)),
+                KeyCode::Char(':') => {
+                    self.command_buffer = "'<,'>".to_string();
+                    Some(Action::EnterMode(Mode::Command))
+                }
+                _ => None,
+            };
+            if action.is_some() {
+                self.pending = Pending::default();
+                return Ok(action);
+            }
+        }
+        self.handle_normal_mode(ev)
+    }
+
+    fn handle_command_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        let action = match ev {
+            event::Event::Key(event) => match (event.code, event.modifiers) {
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) |
+                (KeyCode::Esc, _) => {
+                    self.command_buffer.clear();
+                    Some(Action::EnterMode(Mode::Normal))
+                },
+                (KeyCode::Char(c), _) => {
+                    self.command_buffer.push(c);
+                    None
+                },
+                (KeyCode::Backspace, _) => {
+                    if self.command_buffer.is_empty() { Some(Action::EnterMode(Mode::Normal)) }
+                    else {
+                        self.command_buffer.pop();
+                        None
+                    }
+                },
+                (KeyCode::Enter, _) => {
+                    let cmd = self.command_buffer.trim_start().to_string();
+                    self.leave_command_mode()?;
+                    self.process_command(cmd)?
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+
+        Ok(action)
+    }
+
+    /// Edits a `/` or `?` pattern on the command line. With `incsearch` the
+    /// cursor goes to where it matches as it is typed.
+    fn handle_search_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        let event::Event::Key(event) = ev else { return Ok(None) };
+        match (event.code, event.modifiers) {
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) | (KeyCode::Esc, _) => return Ok(self.cancel_search()),
+            (KeyCode::Backspace, _) if self.command_buffer.is_empty() => return Ok(self.cancel_search()),
+            (KeyCode::Backspace, _) => {
+                self.command_buffer.pop();
+            }
+            (KeyCode::Char(c), _) => self.command_buffer.push(c),
+            (KeyCode::Enter, _) => return Ok(self.finish_search()),
+            _ => return Ok(None),
+        }
+        self.update_incsearch();
+        Ok(None)
+    }
+
+    /// Puts the cursor and view back where they were before the search.
+    fn restore_search_start(&mut self) -> Option<SearchStart> {
+        let start = self.search_start.take()?;
+        self.cur_pos = start.cursor;
+        self.vtop = start.vtop;
+        self.vleft = start.vleft;
+        self.current_match = None;
+        self.command_buffer.clear();
+        Some(start)
+    }
+
+    fn cancel_search(&mut self) -> Option<Action> {
+        let start = self.restore_search_start()?;
+        Some(Action::EnterMode(if start.visual { Mode::Visual } else { Mode::Normal }))
+    }
+
+    /// Searches for the pattern typed, or for the last one again if it is
+    /// empty, as a motion for the operator or mode the search started in.
+    fn finish_search(&mut self) -> Option<Action> {
+        let pattern = self.command_buffer.clone();
+        let start = self.restore_search_start()?;
+        self.mode = if start.visual { Mode::Visual } else { Mode::Normal };
+        if !pattern.is_empty() {
+            self.last_search = Some(Search { pattern, forward: start.forward });
+        } else if let Some(search) = &mut self.last_search {
+            search.forward = start.forward;
+        } else {
+            self.error("E35: No previous regular expression");
+            return None;
+        }
+        let motion = Motion::SearchNext(false);
+        Some(match start.operator {
+            Some(op) => Action::Operate(op, motion, start.count),
+            None => Action::Move(motion, start.count),
+        })
+    }
+
+    /// Moves the cursor to the match for the pattern typed so far, or back
+    /// to where the search started if it doesn't match.
+    fn update_incsearch(&mut self) {
+        let Some(start) = self.search_start else { return };
+        (self.cur_pos, self.vtop, self.vleft) = (start.cursor, start.vtop, start.vleft);
+        self.current_match = None;
+        if !self.options.incsearch || self.command_buffer.is_empty() {
+            return;
+        }
+        let Ok(regex) = self.search_regex(&self.command_buffer) else { return };
+        let mut pos = start.cursor;
+        for _ in 0..start.count.unwrap_or(1).max(1) {
+            let Some((found, _)) = search::find(&self.buffer, &regex, pos, start.forward, self.options.wrapscan) else {
+                self.current_match = None;
+                return;
+            };
+            pos = found.start;
+            self.current_match = Some(found);
+        }
+        self.cur_pos = pos;
+    }
+
+    fn handle_insert_mode(&mut self, ev: event::Event) -> io::Result<Option<Action>> {
+        let action = match ev {
+            event::Event::Key(event) => match (event.code, event.modifiers) {
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) |
+                (KeyCode::Esc, _)=> Some(Action::EnterMode(Mode::Normal)),
+                (KeyCode::Char(c), _) => Some(Action::InsertCharAtCursorPos(c)),
+                (KeyCode::Enter, _) => Some(Action::NewLine),
+                (KeyCode::Tab, _) => Some(Action::InsertTab),
+                (KeyCode::Backspace, _) => {
+                    Some(Action::DeleteChar)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        Ok(action)
+    }
+
+    /// Runs a command line, one `|`-separated command after another until
+    /// one of them fails.
+    fn process_command(&mut self, line: String) -> io::Result<Option<Action>> {
+        let mut rest = Some(line.as_str());
+        while let Some(line) = rest {
+            let command;
+            (command, rest) = match ex::parse(line) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    self.error(e);
+                    break;
+                }
+            };
+            if let Some(action) = self.run_command(command)? {
+                if self.perform(action)? {
+                    return Ok(Some(Action::Quit));
+                }
+            }
+            if self.message.as_ref().is_some_and(|message| message.level == Level::Error) {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    fn run_command(&mut self, command: ex::Command) -> io::Result<Option<Action>> {
+        let lines = match self.resolve_range(&command.range, command.name.is_empty()) {
+            Ok(lines) => lines,
+            Err(e) => {
+                self.error(e);
+                return Ok(None);
+            }
+        };
+        let (force, arg) = (command.bang, command.arg);
+        let (_, y) = self.cur_pos;
+        let all = 0..=self.buffer.len() - 1;
+
+        Ok(match (command.name, arg) {
+            // A range on its own, as in `:42`, goes to its last line.
+            ("", _) => {
+                if let Some(lines) = lines {
+                    let y = *lines.end();
+                    self.cur_pos = (self.buffer.first_non_blank(y), y);
+                }
+                None
+            },
+            ("quit", _) => self.quit(force),
+            ("write", _) => {
+                self.write(force);
+                None
+            },
+            ("wq", _) => self.write_and_quit(force, false),
+            ("xit" | "exit", _) => self.write_and_quit(force, true),
+            ("undo", Arg::Number(n)) => Some(Action::UndoTo(n)),
+            ("undo", _) => Some(Action::Undo),
+            ("redo", _) => Some(Action::Redo),
+            ("set", Arg::Text(arg)) => {
+                for arg in options::split_args(&arg) {
+                    match options::set(&mut (&mut self.options, &mut self.buffer), &arg) {
+                        Ok(Some(value)) => self.info(value),
+                        Ok(None) => {}
+                        Err(e) => {
+                            self.error(e);
+                            break;
+                        }
+                    }
+                }
+                None
+            },
+            ("set", _) => None,
+            (name @ ("earlier" | "later"), arg) => {
+                let forward = name == "later";
+                Some(match arg {
+                    Arg::Offset(Offset::Steps(n)) => Action::UndoStep(if forward { n } else { -n }),
+                    Arg::Offset(Offset::Time(duration)) => Action::UndoTravel(duration, forward),
+                    _ => Action::UndoStep(if forward { 1 } else { -1 }),
+                })
+            },
+            ("messages", Arg::Text(arg)) if arg == "clear" => {
+                self.messages.clear();
+                None
+            },
+            ("messages", Arg::None) => Some(Action::ShowMessages),
+            ("messages", Arg::Text(arg)) => {
+                self.error(format!("E474: Invalid argument: {arg}"));
+                None
+            },
+            ("nohlsearch", _) => {
+                self.nohlsearch = true;
+                None
+            },
+            ("substitute", arg) => {
+                let sub = if let Arg::Substitute(sub) = arg { Some(sub) } else { None };
+                self.substitute(lines.unwrap_or(y..=y), sub)?;
+                None
+            },
+            (name @ ("global" | "vglobal"), Arg::Global { pattern, command }) => {
+                let invert = force || name == "vglobal";
+                self.global(lines.unwrap_or(all), pattern, &command, invert)?
+            },
+            ("normal", Arg::Text(keys)) => self.normal(lines, &keys)?,
+            ("normal", _) => {
+                self.error("E471: Argument required");
+                None
+            },
+            ("delete", arg) => {
+                let lines = self.count_lines(lines.unwrap_or(y..=y), arg.count());
+                self.operate(Operator::Delete, Self::linewise(lines));
+                None
+            },
+            ("yank", arg) => {
+                let lines = self.count_lines(lines.unwrap_or(y..=y), arg.count());
+                self.yank_lines(lines);
+                None
+            },
+            ("move", Arg::Address(address)) => {
+                self.move_lines(lines.unwrap_or(y..=y), &address);
+                None
+            },
+            ("copy" | "t", Arg::Address(address)) => {
+                self.copy_lines(lines.unwrap_or(y..=y), &address);
+                None
+            },
+            ("join", arg) => {
+                let lines = self.count_lines(lines.unwrap_or(y..=y), arg.count());
+                self.join(lines, force);
+                None
+            },
+            (name @ (">" | "<"), arg) => {
+                let (times, count) = match arg {
+                    Arg::Shift { times, count } => (times, count),
+                    _ => (1, None),
+                };
+                let lines = self.count_lines(lines.unwrap_or(y..=y), count);
+                self.shift_lines(lines, name, times);
+                None
+            },
+            ("sort", arg) => {
+                let sort = if let Arg::Sort(sort) = arg { sort } else { Sort::default() };
+                self.sort_lines(lines.unwrap_or(all), &sort, force);
+                None
+            },
+            _ => None,
+        })
+    }
+
+    /// The lines a command's addresses stand for, in order. Lines past the
+    /// end are an error unless `clamp` is set, as going to a line is.
+    fn resolve_range(&mut self, range: &[Address], clamp: bool) -> Result<Option<RangeInclusive<usize>>, String> {
+        let mut lines = Vec::with_capacity(range.len());
+        for address in range {
+            let line = self.address_line(address)?;
+            if line > self.buffer.len() && !clamp {
+                return Err("E16: Invalid range".to_string());
+            }
+            lines.push(line.min(self.buffer.len()));
+        }
+        let (Some(&first), Some(&last)) = (lines.first(), lines.last()) else { return Ok(None) };
+        let (first, last) = (first.min(last), first.max(last));
+        Ok(Some(first.max(1) - 1..=last.max(1) - 1))
+    }
+
+    /// The line `address` stands for, counting from 1 with 0 before the
+    /// first line.
+    fn address_line(&mut self, address: &Address) -> Result<usize, String> {
+        let (_, y) = self.cur_pos;
+        let line = match &address.base {
+            Base::Current => y + 1,
+            Base::Last => self.buffer.len(),
+            Base::Number(n) => *n,
+            Base::Mark(name) => self.buffer.mark(*name).ok_or("E20: Mark not set")?.1 + 1,
+            Base::Search { pattern, forward } => {
+                let pattern = match (pattern.is_empty(), &self.last_search) {
+                    (false, _) => pattern.clone(),
+                    (true, Some(search)) => search.pattern.clone(),
+                    (true, None) => return Err("E35: No previous regular expression".to_string()),
+                };
+                let regex = self.search_regex(&pattern).map_err(|_| format!("E383: Invalid search string: {pattern}"))?;
+                // Starting from the end of the cursor line, or its start
+                // going back, so the cursor line only matches last.
+                let from = if *forward { (self.buffer.line_len(y), y) } else { (0, y) };
+                let (found, _) = search::find(&self.buffer, &regex, from, *forward, self.options.wrapscan)
+                    .ok_or_else(|| format!("E486: Pattern not found: {pattern}"))?;
+                self.last_search = Some(Search { pattern, forward: *forward });
+                found.start.1 + 1
+            }
+        };
+        line.checked_add_signed(address.offset).ok_or_else(|| "E16: Invalid range".to_string())
+    }
+
+    /// `:s`: replaces matches of a pattern on `lines`. With the `c` flag it
+    /// asks about each one first. However many lines it changes, it is one
+    /// undo step like any other command.
+    fn substitute(&mut self, lines: RangeInclusive<usize>, sub: Option<Substitute>) -> io::Result<()> {
+        let sub = match (sub, &self.last_substitute) {
+            (Some(sub), Some(last)) if sub.keep_flags => Substitute {
+                global: sub.global || last.global,
+                confirm: sub.confirm || last.confirm,
+                ignore_case: sub.ignore_case.or(last.ignore_case),
+                keep_flags: false,
+                ..sub
+            },
+            (Some(sub), _) => Substitute { keep_flags: false, ..sub },
+            // `:s` on its own repeats the last one, without its flags.
+            (None, Some(last)) => Substitute { global: false, confirm: false, ignore_case: None, ..last.clone() },
+            (None, None) => {
+                self.error("E35: No previous regular expression");
+                return Ok(());
+            }
+        };
+        let pattern = match (&sub.pattern, &self.last_search) {
+            (pattern, _) if !pattern.is_empty() => pattern.clone(),
+            (_, Some(search)) => search.pattern.clone(),
+            (_, None) => {
+                self.error("E35: No previous regular expression");
+                return Ok(());
+            }
+        };
+        let regex = match sub.ignore_case {
+            Some(ignore) => search::compile(&pattern, ignore, false),
+            None => self.search_regex(&pattern),
+        };
+        let Ok(regex) = regex else {
+            self.error(format!("E383: Invalid search string: {pattern}"));
+            return Ok(());
+        };
+        self.last_search = Some(Search { pattern: pattern.clone(), forward: true });
+        self.last_substitute = Some(sub.clone());
+        self.nohlsearch = false;
+
+        let (mut count, mut changed_lines, mut last_changed) = (0, 0, None);
+        let mut matched = false;
+        let mut all = !sub.confirm;
+        let (mut y, mut end) = lines.into_inner();
+        'lines: while y <= end {
+            let line = self.buffer.get(y).map(|l| l.slice(..self.buffer.line_len(y)).to_string()).unwrap_or_default();
+            let line_start = self.buffer.char_idx(0, y);
+            // Chars and lines added by replacements so far on this line.
+            let (mut added, mut added_lines) = (0isize, 0);
+            let mut changed = false;
+            let limit = if sub.global { usize::MAX } else { 1 };
+            for caps in regex.captures_iter(&line).take(limit) {
+                matched = true;
+                let m = caps.get(0).unwrap();
+                let col = line[..m.start()].chars().count();
+                let start = (line_start + col).saturating_add_signed(added);
+                let len = m.as_str().chars().count();
+                let range = Range {
+                    start: self.buffer.char_to_pos(start),
+                    end: self.buffer.char_to_pos(start + len),
+                    linewise: false,
+                };
+                let mut last = false;
+                if !all {
+                    match self.confirm_substitute(range, &sub.replacement)? {
+                        'n' => continue,
+                        'a' => all = true,
+                        'l' => last = true,
+                        'q' => break 'lines,
+                        _ => {}
+                    }
+                }
+                let text = substitute::expand(&sub.replacement, &caps);
+                self.buffer.replace_range(&range, &text);
+                added += text.chars().count() as isize - len as isize;
+                added_lines += text.matches('\n').count();
+                last_changed = Some(y + added_lines);
+                count += 1;
+                changed = true;
+                if last {
+                    changed_lines += 1;
+                    break 'lines;
+                }
+            }
+            changed_lines += changed as usize;
+            end += added_lines;
+            y += added_lines + 1;
+        }
 
+        // Under `:global`, lines without a match are only reported at the
+        // end, if no line had one.
+        match &mut self.global {
+            Some(global) if matched => global.found = true,
+            Some(global) => global.not_found = Some(pattern.clone()),
+            None if !matched => self.error(format!("E486: Pattern not found: {pattern}")),
+            None => {}
+        }
+        let Some(line) = last_changed else { return Ok(()) };
+        self.cur_pos = (self.buffer.first_non_blank(line), line);
+        if count > REPORT {
+            let lines = if changed_lines == 1 { "line" } else { "lines" };
+            self.info(format!("{count} substitutions on {changed_lines} {lines}"));
+        }
         Ok(())
     }
+
+    /// `:g/pattern/command`: runs an ex command on each line in `lines` that
+    /// matches, or with `invert`, as for `:v`, each one that doesn't. The
+    /// lines are marked before any of them change, so the command finds
+    /// them however earlier ones moved them, and skips those deleted on the
+    /// way. Without a command the lines are shown.
+    fn global(&mut self, lines: RangeInclusive<usize>, pattern: String, command: &str, invert: bool) -> io::Result<Option<Action>> {
+        if self.global.is_some() {
+            self.error("E147: Cannot do :global recursive");
+            return Ok(None);
+        }
+        let (pattern, regex) = match self.line_pattern(pattern) {
+            Ok(found) => found,
+            Err(e) => {
+                self.error(e);
+                return Ok(None);
+            }
+        };
+
+        let marked: Vec<usize> = lines.filter(|&y| search::matches(&self.buffer, &regex, y).is_empty() == invert).collect();
+        let Some(&last) = marked.last() else {
+            if invert {
+                self.info(format!("Pattern found in every line: {pattern}"));
+            } else {
+                self.info(format!("Pattern not found: {pattern}"));
+            }
+            return Ok(None);
+        };
+        if command.trim().is_empty() {
+            let text: Vec<_> = marked
+                .iter()
+                .filter_map(|&y| Some((self.buffer.get(y)?.slice(..self.buffer.line_len(y)).to_string(), style::ContentStyle::new())))
+                .collect();
+            self.cur_pos = (self.buffer.first_non_blank(last), last);
+            self.page(&format!(":{}/{pattern}/", if invert { "v" } else { "g" }), &text, 0)?;
+            return Ok(None);
+        }
+
+        let before = self.buffer.len();
+        self.buffer.mark_lines(marked);
+        self.global = Some(Global::default());
+        let mut action = None;
+        while let Some(y) = self.buffer.next_marked_line() {
+            self.cur_pos = (0, y);
+            action = self.process_command(command.to_string())?;
+            if action.is_some() || self.message.as_ref().is_some_and(|message| message.level == Level::Error) {
+                break;
+            }
+        }
+        self.buffer.clear_marked_lines();
+        let global = self.global.take().unwrap_or_default();
+
+        // One report for the whole run rather than one per line.
+        let after = self.buffer.len();
+        if before.abs_diff(after) > REPORT {
+            let (n, what) = if after < before { (before - after, "fewer") } else { (after - before, "more") };
+            self.info(format!("{n} {what} lines"));
+        }
+        let failed = self.message.as_ref().is_some_and(|message| message.level == Level::Error);
+        if let (false, Some(pattern), false) = (global.found, global.not_found, failed) {
+            self.error(format!("E486: Pattern not found: {pattern}"));
+        }
+        Ok(action)
+    }
+
+    /// `:normal keys`: types `keys` in Normal mode, on each line in `lines`
+    /// if given, starting at its first column. A command left incomplete
+    /// is ended as if with Esc, and so is Insert mode. Nothing typed quits.
+    fn normal(&mut self, lines: Option<RangeInclusive<usize>>, keys: &str) -> io::Result<Option<Action>> {
+        if keys.is_empty() {
+            self.error("E471: Argument required");
+            return Ok(None);
+        }
+        let lines: Vec<Option<usize>> = match lines {
+            Some(lines) => lines.map(Some).collect(),
+            None => vec![None],
+        };
+        let in_normal = std::mem::replace(&mut self.in_normal, true);
+        for y in lines {
+            if let Some(y) = y {
+                // Earlier lines may have removed this one.
+                if y >= self.buffer.len() {
+                    break;
+                }
+                self.cur_pos = (0, y);
+            }
+            let events = keys
+                .chars()
+                .map(|c| event::Event::Key(event::KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)))
+                .chain(std::iter::once(event::Event::Key(event::KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE))));
+            for ev in events {
+                if let Some(action) = self.dispatch(ev)? {
+                    self.perform(action)?;
+                }
+            }
+            self.pending = Pending::default();
+        }
+        self.in_normal = in_normal;
+        Ok(None)
+    }
+
+    /// The pattern a command like `:g` or `:sort` was given, or the last
+    /// one searched for if it is empty, and its regex. It becomes the last
+    /// search pattern, as with `:s`.
+    fn line_pattern(&mut self, pattern: String) -> Result<(String, regex::Regex), String> {
+        let pattern = match (pattern.is_empty(), &self.last_search) {
+            (false, _) => pattern,
+            (true, Some(search)) => search.pattern.clone(),
+            (true, None) => return Err("E35: No previous regular expression".to_string()),
+        };
+        let regex = self.search_regex(&pattern).map_err(|_| format!("E383: Invalid search string: {pattern}"))?;
+        self.last_search = Some(Search { pattern: pattern.clone(), forward: true });
+        self.nohlsearch = false;
+        Ok((pattern, regex))
+    }
+
+    fn linewise(lines: RangeInclusive<usize>) -> Range {
+        Range { start: (0, *lines.start()), end: (0, *lines.end()), linewise: true }
+    }
+
+    /// The lines a command works on with the count it can be given, as in
+    /// `:d 3`, which is that many lines from the last one in `lines`.
+    fn count_lines(&self, lines: RangeInclusive<usize>, count: Option<usize>) -> RangeInclusive<usize> {
+        let Some(count) = count else { return lines };
+        let first = *lines.end();
+        first..=first.saturating_add(count - 1).min(self.buffer.len() - 1)
+    }
+
+    /// The line `:m` and `:t` put lines after, counting from 1 with 0 for
+    /// the top of the buffer.
+    fn destination(&mut self, address: &Address) -> Result<usize, String> {
+        let line = self.address_line(address)?;
+        if line > self.buffer.len() {
+            return Err("E16: Invalid range".to_string());
+        }
+        Ok(line)
+    }
+
+    /// `:y`: yanks `lines` without moving the cursor.
+    fn yank_lines(&mut self, lines: RangeInclusive<usize>) {
+        let range = Self::linewise(lines);
+        self.register = Register { text: self.buffer.text_in(&range), linewise: true };
+        let count = range.line_count();
+        if count > REPORT {
+            self.info(format!("{count} lines yanked"));
+        }
+    }
+
+    /// `:m address`: moves `lines` to below the line at `address`.
+    fn move_lines(&mut self, lines: RangeInclusive<usize>, address: &Address) {
+        let dest = match self.destination(address) {
+            Ok(dest) => dest,
+            Err(e) => {
+                self.error(e);
+                return;
+            }
+        };
+        let (first, last) = lines.into_inner();
+        if dest > first && dest <= last {
+            self.error("E134: Cannot move a range of lines into itself");
+            return;
+        }
+        let count = last - first + 1;
+        let at = if dest > last { dest - count } else { dest };
+        // Moving lines to where they already are changes nothing.
+        if dest != first && dest != last + 1 {
+            self.buffer.move_lines(first..=last, at);
+        }
+        self.move_to_line_start(at + count - 1);
+        if count > REPORT {
+            self.info(format!("{count} lines moved"));
+        }
+    }
+
+    /// `:t address` or `:co address`: copies `lines` to below the line at
+    /// `address`.
+    fn copy_lines(&mut self, lines: RangeInclusive<usize>, address: &Address) {
+        let dest = match self.destination(address) {
+            Ok(dest) => dest,
+            Err(e) => {
+                self.error(e);
+                return;
+            }
+        };
+        let range = Self::linewise(lines);
+        let text = self.buffer.text_in(&range);
+        self.buffer.insert_line(dest, &text[..text.len() - 1]);
+        let count = range.line_count();
+        self.move_to_line_start(dest + count - 1);
+        if count > REPORT {
+            self.info(format!("{count} more lines"));
+        }
+    }
+
+    /// `:j`: joins `lines`, or a single line and the one after it, into
+    /// one. The indent of each joined line becomes a single space, unless
+    /// `keep` is set, as for `:j!`, which joins them as they are.
+    fn join(&mut self, lines: RangeInclusive<usize>, keep: bool) {
+        let (first, last) = lines.into_inner();
+        let last = if first == last { first + 1 } else { last };
+        if last >= self.buffer.len() {
+            return;
+        }
+        let mut col = 0;
+        for _ in first..last {
+            col = self.buffer.line_len(first);
+            if keep {
+                self.buffer.join_lines(first);
+                continue;
+            }
+            let blanks = self.leading_blanks(first + 1);
+            let prev = col.checked_sub(1).and_then(|x| self.buffer.char_at(x, first));
+            let next = (blanks < self.buffer.line_len(first + 1)).then(|| self.buffer.char_at(blanks, first + 1)).flatten();
+            // No space after a blank, before a `)` or next to nothing.
+            let space = match (prev, next) {
+                (None | Some(' ' | '\t'), _) | (_, None | Some(')')) => "",
+                _ => " ",
+            };
+            let range = Range { start: (col, first), end: (blanks, first + 1), linewise: false };
+            self.buffer.replace_range(&range, space);
+        }
+        let len = self.buffer.line_len(first);
+        self.cur_pos = (if col < len { col } else { self.buffer.prev_grapheme(len, first) }, first);
+    }
+
+    /// `:>` and `:<`: shifts `lines` `times` times, once for every `>` or
+    /// `<` typed, as in `:>>`.
+    fn shift_lines(&mut self, lines: RangeInclusive<usize>, name: &str, times: usize) {
+        let (count, last) = (lines.clone().count(), *lines.end());
+        self.shift(lines, name == "<", times);
+        self.move_to_line_start(last);
+        if count > REPORT {
+            let plural = if times == 1 { "" } else { "s" };
+            self.info(format!("{count} lines {name}ed {times} time{plural}"));
+        }
+    }
+
+    /// `:sort`: sorts `lines`, backwards with `reverse`, as for `:sort!`.
+    fn sort_lines(&mut self, lines: RangeInclusive<usize>, sort: &Sort, reverse: bool) {
+        let regex = match sort.pattern.clone().map(|pattern| self.line_pattern(pattern)) {
+            Some(Ok((_, regex))) => Some(regex),
+            Some(Err(e)) => {
+                self.error(e);
+                return;
+            }
+            None => None,
+        };
+
+        let range = Self::linewise(lines);
+        let mut text: Vec<String> = range
+            .lines()
+            .filter_map(|y| Some(self.buffer.get(y)?.slice(..self.buffer.line_len(y)).to_string()))
+            .collect();
+        let before = text.len();
+        sort::sort(&mut text, sort, regex.as_ref(), reverse);
+        self.buffer.replace_range(&range, &text.join("\n"));
+        self.move_to_line_start(range.start.1);
+        let removed = before - text.len();
+        if removed > REPORT {
+            self.info(format!("{removed} fewer lines"));
+        }
+    }
+
+    /// Shows the match in `range` and asks whether to replace it, for
+    /// `:s///c`. Returns `y`, `n`, `a` for all remaining, `q` to stop or
+    /// `l` to replace this one and stop.
+    fn confirm_substitute(&mut self, range: Range, replacement: &str) -> io::Result<char> {
+        self.cur_pos = range.start;
+        self.current_match = Some(range);
+        let text = format!("replace with {replacement} (y/n/a/q/l)?");
+        let answer = loop {
+            self.message = Some(Message { level: Level::Info, text: text.clone() });
+            self.draw()?;
+            let event::Event::Key(key) = event::read()? else { continue };
+            match key.code {
+                KeyCode::Char(c @ ('y' | 'n' | 'a' | 'q' | 'l')) => break c,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break 'q',
+                KeyCode::Esc => break 'q',
+                _ => {}
+            }
+        };
+        self.current_match = None;
+        self.message = None;
+        Ok(answer)
+    }
+
+    /// `:q`: refuses to throw away unsaved changes unless forced.
+    fn quit(&mut self, force: bool) -> Option<Action> {
+        if self.buffer.modified() && !force {
+            self.error("E37: No write since last change (add ! to override)");
+            return None;
+        }
+        Some(Action::Quit)
+    }
+
+    /// Writes the buffer, showing the outcome. Returns whether it worked.
+    fn write(&mut self, force: bool) -> bool {
+        match self.buffer.save(self.options.backup, force) {
+            Ok(message) => {
+                self.info(message);
+                true
+            }
+            Err(e) => {
+                self.error(e.to_string());
+                false
+            }
+        }
+    }
+
+    /// `:wq`, or with `only_modified` `:x` and `ZZ`, which skip writing an
+    /// unchanged buffer. Stays open if the write fails.
+    fn write_and_quit(&mut self, force: bool, only_modified: bool) -> Option<Action> {
+        if only_modified && !self.buffer.modified() {
+            return Some(Action::Quit);
+        }
+        self.write(force).then_some(Action::Quit)
+    }
+
+    pub fn cleanup(&mut self) -> io::Result<()> {
+        self.buffer.remove_swap();
+        swap::remember(None);
+        self.stdout.execute(LeaveAlternateScreen)?;
+        disable_raw_mode()?;
+
+        Ok(())
+    }
+}
+
+
+
+/// `a` and `b` in the order they come in the buffer.
+fn ordered(a: (usize, usize), b: (usize, usize)) -> ((usize, usize), (usize, usize)) {
+    if (b.1, b.0) < (a.1, a.0) { (b, a) } else { (a, b) }
+}
+
+/// The count for an operator and its motion together: `3d2w` deletes six
+/// words.
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+/// How long ago `time` was, roughly.
+fn age(time: SystemTime) -> String {
+    let secs = SystemTime::now().duration_since(time).unwrap_or_default().as_secs();
+    match secs {
+        0..60 => format!("{secs} seconds ago"),
+        60..3600 => format!("{} minutes ago", secs / 60),
+        3600..86400 => format!("{} hours ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    /// Runs the command line `command` on `text`, and returns the text
+    /// after it and the message it left.
+    fn run(text: &str, command: &str) -> (String, Option<String>) {
+        let mut editor = Editor::with_size(Buffer::from_text(text), (80, 24));
+        editor.process_command(command.to_string()).unwrap();
+        (editor.buffer.text().to_string(), editor.message.map(|message| message.text))
+    }
+
+    #[test]
+    fn global_runs_substitute_on_each_line() {
+        let text = "TODO foo\nTODO\nbar\nTODO foo foo";
+        assert_eq!(run(text, "g/TODO/s/foo/x/"), ("TODO x\nTODO\nbar\nTODO x foo".to_string(), None));
+        assert_eq!(run(text, "g/TODO/s/foo/x/g"), ("TODO x\nTODO\nbar\nTODO x x".to_string(), None));
+        // A line without a match doesn't stop the rest of the command.
+        assert_eq!(run(text, "g/TODO/s/foo/x/|s/$/!/").0, "TODO x!\nTODO!\nbar\nTODO x foo!");
+        assert_eq!(run(text, "g/TODO/s/baz/x/"), (text.to_string(), Some("E486: Pattern not found: baz".to_string())));
+    }
+
+    #[test]
+    fn global_deletes_and_moves_lines() {
+        let text = "a1\nb\na2\nc\na3";
+        assert_eq!(run(text, "g/a/d").0, "b\nc");
+        assert_eq!(run(text, "v/a/d").0, "a1\na2\na3");
+        // Each line is found where the lines before it moved it.
+        assert_eq!(run(text, "g/^/m0").0, "a3\nc\na2\nb\na1");
+        assert_eq!(run(text, "g/a/m$").0, "b\nc\na1\na2\na3");
+        assert_eq!(run(text, "g/a/m0").0, "a3\na2\na1\nb\nc");
+        assert_eq!(run(text, "2,3g/^/m$").0, "a1\nc\na3\nb\na2");
+        assert_eq!(run(text, "g/a/s/a/x/|d").0, "b\nc");
+        assert_eq!(run(text, "g/a/g/b/d").1.as_deref(), Some("E147: Cannot do :global recursive"));
+    }
+}
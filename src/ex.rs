@@ -0,0 +1,498 @@
+use std::time::Duration;
+
+use crate::sort::{self, Sort};
+use crate::substitute::{self, Substitute};
+
+/// What an address in a range starts from, before any offset.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Base {
+    /// `.`, or nothing before an offset as in `+2`.
+    Current,
+    /// `$`.
+    Last,
+    /// A line number, counting from 1. `0` is before the first line, for
+    /// commands that put lines after an address.
+    Number(usize),
+    /// `'a`, `'<` or `'>`.
+    Mark(char),
+    /// `/pattern/` for the next line that matches, or `?pattern?` for the
+    /// previous one. An empty pattern is the last one searched for.
+    Search { pattern: String, forward: bool },
+}
+
+/// A line given as part of a range, as `'a+2`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    pub base: Base,
+    pub offset: isize,
+}
+
+/// A parsed command line, up to a `|` that separates it from the next one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// None, one or two addresses. `%` is short for `1,$`.
+    pub range: Vec<Address>,
+    /// The full name of the command, or empty for a range on its own,
+    /// which goes to the line.
+    pub name: &'static str,
+    pub bang: bool,
+    pub arg: Arg,
+}
+
+/// What follows the name of a command, parsed the way the command takes it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    /// Nothing was given.
+    None,
+    /// Text the command reads itself: the options of `:set`, the keys of
+    /// `:normal` or `clear` for `:messages`.
+    Text(String),
+    /// How many lines to work on, as in `:d 3`.
+    Count(usize),
+    /// The line `:m` and `:t` put lines after.
+    Address(Address),
+    /// `/pattern/command` for `:g`.
+    Global { pattern: String, command: String },
+    Substitute(Substitute),
+    Sort(Sort),
+    /// The `>`s or `<`s after `:>` or `:<`, counting the command's own,
+    /// and a count, as in `:>> 3`.
+    Shift { times: usize, count: Option<usize> },
+    /// A revision for `:undo`.
+    Number(usize),
+    /// How far `:earlier` or `:later` go.
+    Offset(Offset),
+}
+
+impl Arg {
+    /// The count given to a command that takes one.
+    pub fn count(&self) -> Option<usize> {
+        match self {
+            Arg::Count(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// A number of changes, at least one, or a span of time to go back or
+/// forward in the undo history.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Offset {
+    Steps(isize),
+    Time(Duration),
+}
+
+/// The kinds of [`Arg`] a command takes.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    None,
+    Text,
+    Count,
+    Address,
+    Global,
+    Substitute,
+    Sort,
+    Shift,
+    Number,
+    Offset,
+}
+
+/// An ex command: its full name, how few letters of it may be typed,
+/// whether it takes a range and a `!`, and what argument it takes.
+struct Spec {
+    name: &'static str,
+    min: usize,
+    range: bool,
+    bang: bool,
+    arg: Kind,
+}
+
+const fn spec(name: &'static str, min: usize, range: bool, bang: bool, arg: Kind) -> Spec {
+    Spec { name, min, range, bang, arg }
+}
+
+/// Every command, in the order abbreviations are tried, as vim's
+/// `:s` is `:substitute` rather than `:set`.
+const COMMANDS: &[Spec] = &[
+    spec("quit", 1, false, true, Kind::None),
+    spec("write", 1, false, true, Kind::None),
+    spec("wq", 2, false, true, Kind::None),
+    spec("xit", 1, false, true, Kind::None),
+    spec("exit", 3, false, true, Kind::None),
+    spec("undo", 1, false, false, Kind::Number),
+    spec("redo", 3, false, false, Kind::None),
+    spec("earlier", 2, false, false, Kind::Offset),
+    spec("later", 3, false, false, Kind::Offset),
+    spec("substitute", 1, true, false, Kind::Substitute),
+    spec("set", 2, false, false, Kind::Text),
+    spec("messages", 3, false, false, Kind::Text),
+    spec("nohlsearch", 3, false, false, Kind::None),
+    spec("global", 1, true, true, Kind::Global),
+    spec("vglobal", 1, true, false, Kind::Global),
+    spec("normal", 4, true, true, Kind::Text),
+    spec("delete", 1, true, false, Kind::Count),
+    spec("yank", 1, true, false, Kind::Count),
+    spec("move", 1, true, false, Kind::Address),
+    spec("copy", 2, true, false, Kind::Address),
+    spec("t", 1, true, false, Kind::Address),
+    spec("join", 1, true, true, Kind::Count),
+    spec(">", 1, true, false, Kind::Shift),
+    spec("<", 1, true, false, Kind::Shift),
+    spec("sort", 3, true, true, Kind::Sort),
+];
+
+/// Commands that take the rest of the line, `|` and all, as their argument.
+const TAKES_BAR: &[&str] = &["global", "vglobal", "normal"];
+
+/// Looks up the full name of a command typed as `name`.
+fn lookup(name: &str) -> Option<&'static Spec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == name)
+        .or_else(|| COMMANDS.iter().find(|spec| name.len() >= spec.min && spec.name.starts_with(name)))
+}
+
+/// Parses the first command of `line`. Returns it with whatever follows
+/// the `|` after it, if anything. A `|` escaped with a backslash is part
+/// of the argument, without the backslash.
+pub fn parse(line: &str) -> Result<(Command, Option<&str>), String> {
+    let line = line.trim_start_matches([' ', ':']);
+    let (range, rest) = parse_range(line)?;
+    let rest = rest.trim_start();
+
+    // `:>` and `:<` are the only names that aren't letters.
+    let name_len = if rest.starts_with(['>', '<']) {
+        1
+    } else {
+        rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len())
+    };
+    let (typed, rest) = rest.split_at(name_len);
+    let spec = match typed {
+        "" if rest.is_empty() || rest.starts_with('|') => None,
+        "" => return Err(format!("E492: Not an editor command: {line}")),
+        typed => Some(lookup(typed).ok_or_else(|| format!("E492: Not an editor command: {line}"))?),
+    };
+    let (bang, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+
+    if let Some(spec) = spec {
+        if !range.is_empty() && !spec.range {
+            return Err("E481: No range allowed".to_string());
+        }
+        if bang && !spec.bang {
+            return Err("E477: No ! allowed".to_string());
+        }
+    }
+    let name = spec.map_or("", |spec| spec.name);
+
+    // Those that take the rest of the line keep trailing blanks, which
+    // `:normal` types like any other key.
+    let (text, next) = if TAKES_BAR.contains(&name) {
+        (rest.trim_start().to_string(), None)
+    } else {
+        let (arg, next) = split_bar(rest);
+        (arg.trim().to_string(), next)
+    };
+    let arg = match spec {
+        Some(spec) if !text.is_empty() => parse_arg(spec, text)?,
+        Some(spec) if matches!(spec.arg, Kind::Address | Kind::Global) => return Err(missing(spec.arg)),
+        _ => Arg::None,
+    };
+    Ok((Command { range, name, bang, arg }, next))
+}
+
+fn missing(kind: Kind) -> String {
+    match kind {
+        Kind::Address => "E14: Invalid address".to_string(),
+        _ => "E471: Argument required".to_string(),
+    }
+}
+
+/// Parses the argument `text` given to the command `spec`.
+fn parse_arg(spec: &Spec, text: String) -> Result<Arg, String> {
+    let trailing = |rest: &str| format!("E488: Trailing characters: {rest}");
+    Ok(match spec.arg {
+        Kind::None => return Err(trailing(&text)),
+        Kind::Text => Arg::Text(text),
+        Kind::Count => Arg::Count(parse_count(&text)?),
+        Kind::Address => match parse_address(&text)? {
+            Some((address, "")) => Arg::Address(address),
+            Some((_, rest)) => return Err(trailing(rest)),
+            None => return Err(missing(spec.arg)),
+        },
+        Kind::Global => {
+            let (pattern, command) = split_pattern(&text)?;
+            Arg::Global { pattern, command: command.to_string() }
+        }
+        Kind::Substitute => Arg::Substitute(substitute::parse(&text)?),
+        Kind::Sort => Arg::Sort(sort::parse(&text)?),
+        Kind::Shift => {
+            let shift = text.len() - text.trim_start_matches(spec.name).len();
+            let count = text[shift..].trim_start();
+            let count = if count.is_empty() { None } else { Some(parse_count(count)?) };
+            Arg::Shift { times: shift + 1, count }
+        }
+        Kind::Number => match number(&text)? {
+            (Some(n), "") => Arg::Number(n),
+            _ => return Err(format!("E474: Invalid argument: {text}")),
+        },
+        Kind::Offset => Arg::Offset(parse_offset(&text)?),
+    })
+}
+
+/// A count after a command, which must be more than zero.
+fn parse_count(text: &str) -> Result<usize, String> {
+    match number(text)? {
+        (Some(0), _) => Err("E939: Positive count required".to_string()),
+        (Some(n), "") => Ok(n),
+        _ => Err(format!("E488: Trailing characters: {text}")),
+    }
+}
+
+/// Parses the argument of `:earlier`/`:later`: either a number of changes
+/// or a time span such as `10s`, `5m`, `1h` or `2d`.
+fn parse_offset(text: &str) -> Result<Offset, String> {
+    let invalid = || format!("E475: Invalid argument: {text}");
+    // Step counts too big for an `isize` fall through to the time form,
+    // which rejects them.
+    if let Ok(count) = text.parse::<isize>() {
+        return if count > 0 { Ok(Offset::Steps(count)) } else { Err(invalid()) };
+    }
+    let (i, unit) = text.char_indices().next_back().ok_or_else(invalid)?;
+    let n: u64 = text[..i].parse().map_err(|_| invalid())?;
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        _ => return Err(invalid()),
+    };
+    let secs = n.checked_mul(scale).ok_or_else(invalid)?;
+    Ok(Offset::Time(Duration::from_secs(secs)))
+}
+
+/// Splits the argument of `:g` into its pattern and the command after it,
+/// as in `/pattern/command`. Any character that isn't a letter or digit
+/// can be the delimiter.
+pub fn split_pattern(arg: &str) -> Result<(String, &str), String> {
+    let mut chars = arg.chars();
+    match chars.next() {
+        Some(c) if !c.is_alphanumeric() && !matches!(c, '\\' | '"' | '|' | ' ') => Ok(until(chars.as_str(), c)),
+        Some(_) => Err(format!("E146: Regular expressions can't be delimited by letters: {arg}")),
+        None => Err("E471: Argument required".to_string()),
+    }
+}
+
+/// Splits off the text after the first `|` that isn't escaped.
+fn split_bar(text: &str) -> (String, Option<&str>) {
+    let mut arg = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, '|')) => arg.push('|'),
+                Some((_, next)) => arg.extend(['\\', next]),
+                None => arg.push('\\'),
+            },
+            '|' => return (arg, Some(&text[i + 1..])),
+            c => arg.push(c),
+        }
+    }
+    (arg, None)
+}
+
+/// Parses the addresses at the start of a command, separated by a comma.
+fn parse_range(text: &str) -> Result<(Vec<Address>, &str), String> {
+    if let Some(rest) = text.strip_prefix('%') {
+        let first = Address { base: Base::Number(1), offset: 0 };
+        return Ok((vec![first, Address { base: Base::Last, offset: 0 }], rest));
+    }
+    let mut range = Vec::new();
+    let mut rest = text;
+    while let Some((address, after)) = parse_address(rest)? {
+        range.push(address);
+        rest = after.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) if range.len() < 2 => rest = after.trim_start(),
+            Some(_) => return Err("E16: Invalid range".to_string()),
+            None => break,
+        }
+    }
+    Ok((range, rest))
+}
+
+/// Parses one address and any offsets after it, or returns `None` if
+/// `text` doesn't start with one.
+fn parse_address(text: &str) -> Result<Option<(Address, &str)>, String> {
+    let mut chars = text.chars();
+    let (base, mut rest) = match chars.next() {
+        Some('.') => (Base::Current, &text[1..]),
+        Some('$') => (Base::Last, &text[1..]),
+        Some('\'') => match chars.next() {
+            Some(mark) if mark.is_ascii_lowercase() || matches!(mark, '<' | '>') => (Base::Mark(mark), chars.as_str()),
+            _ => return Err("E78: Unknown mark".to_string()),
+        },
+        Some(delimiter @ ('/' | '?')) => {
+            let (pattern, rest) = until(chars.as_str(), delimiter);
+            (Base::Search { pattern, forward: delimiter == '/' }, rest)
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let (n, rest) = number(text)?;
+            (Base::Number(n.unwrap_or(0)), rest)
+        }
+        Some('+' | '-') => (Base::Current, text),
+        _ => return Ok(None),
+    };
+
+    // Offsets add up, as in `.+3-1`; a sign without a number means one.
+    let mut offset = 0isize;
+    let invalid = || "E16: Invalid range".to_string();
+    while let Some(sign) = rest.chars().next().filter(|c| matches!(c, '+' | '-')) {
+        let (n, after) = number(&rest[1..])?;
+        let n = isize::try_from(n.unwrap_or(1)).map_err(|_| invalid())?;
+        offset = if sign == '+' { offset.checked_add(n) } else { offset.checked_sub(n) }.ok_or_else(invalid)?;
+        rest = after;
+    }
+    Ok(Some((Address { base, offset }, rest)))
+}
+
+/// The number at the start of `text`, if there is one, and what follows.
+fn number(text: &str) -> Result<(Option<usize>, &str), String> {
+    let len = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    if len == 0 {
+        return Ok((None, text));
+    }
+    let n = text[..len].parse().map_err(|_| "E16: Invalid range".to_string())?;
+    Ok((Some(n), &text[len..]))
+}
+
+/// The text up to an unescaped `delimiter`, and what follows it. A
+/// delimiter escaped with a backslash is kept without the backslash.
+fn until(text: &str, delimiter: char) -> (String, &str) {
+    let mut pattern = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, next)) if next == delimiter => pattern.push(next),
+                Some((_, next)) => pattern.extend(['\\', next]),
+                None => pattern.push('\\'),
+            },
+            c if c == delimiter => return (pattern, &text[i + c.len_utf8()..]),
+            c => pattern.push(c),
+        }
+    }
+    (pattern, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        parse(line).unwrap().0
+    }
+
+    fn address(base: Base, offset: isize) -> Address {
+        Address { base, offset }
+    }
+
+    #[test]
+    fn abbreviations() {
+        assert_eq!(command("wri").name, "write");
+        assert_eq!(command("w").name, "write");
+        assert_eq!(command("s/a/b/").name, "substitute");
+        assert_eq!(command("se ts=4").name, "set");
+        assert_eq!(command("sor").name, "sort");
+        assert_eq!(command("m0").name, "move");
+        assert_eq!(command("mes").name, "messages");
+        assert_eq!(command("t.").name, "t");
+        assert_eq!(command("co$").name, "copy");
+        assert_eq!(parse("me").unwrap_err(), "E492: Not an editor command: me");
+        assert_eq!(parse("foo").unwrap_err(), "E492: Not an editor command: foo");
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(command("%d").range, [address(Base::Number(1), 0), address(Base::Last, 0)]);
+        assert_eq!(command("'a,'<y").range, [address(Base::Mark('a'), 0), address(Base::Mark('<'), 0)]);
+        assert_eq!(
+            command("/pat/+1,$-2d").range,
+            [address(Base::Search { pattern: "pat".to_string(), forward: true }, 1), address(Base::Last, -2)]
+        );
+        assert_eq!(command("?a\\?b?d").range, [address(Base::Search { pattern: "a?b".to_string(), forward: false }, 0)]);
+        assert_eq!(command(".+3-1").range, [address(Base::Current, 2)]);
+        assert_eq!(command("-").range, [address(Base::Current, -1)]);
+        assert_eq!(command("42"), Command { range: vec![address(Base::Number(42), 0)], name: "", bang: false, arg: Arg::None });
+        assert_eq!(parse("'1d").unwrap_err(), "E78: Unknown mark");
+        assert_eq!(parse("1,2,3d").unwrap_err(), "E16: Invalid range");
+    }
+
+    #[test]
+    fn numbers_that_overflow_are_invalid() {
+        assert_eq!(parse("99999999999999999999d").unwrap_err(), "E16: Invalid range");
+        assert_eq!(parse(".+99999999999999999999d").unwrap_err(), "E16: Invalid range");
+        assert_eq!(parse(".+9223372036854775808d").unwrap_err(), "E16: Invalid range");
+        assert_eq!(parse(".+9223372036854775807+1d").unwrap_err(), "E16: Invalid range");
+    }
+
+    #[test]
+    fn bars() {
+        let (first, next) = parse("s/a\\|b/c/|d").unwrap();
+        assert_eq!(first.arg, Arg::Substitute(substitute::parse("/a|b/c/").unwrap()));
+        assert_eq!(next, Some("d"));
+        assert_eq!(parse("noh|q").unwrap().1, Some("q"));
+
+        // Commands that take the rest of the line, `|` and all.
+        let (global, next) = parse("g/a/s/x/y/|d").unwrap();
+        assert_eq!(global.arg, Arg::Global { pattern: "a".to_string(), command: "s/x/y/|d".to_string() });
+        assert_eq!(next, None);
+        assert_eq!(command("norm A|b  ").arg, Arg::Text("A|b  ".to_string()));
+    }
+
+    #[test]
+    fn bangs_and_ranges_not_allowed() {
+        assert!(command("q!").bang);
+        assert!(command("sort!").bang);
+        assert_eq!(parse("d!").unwrap_err(), "E477: No ! allowed");
+        assert_eq!(parse("1,2set").unwrap_err(), "E481: No range allowed");
+        assert_eq!(parse("%q").unwrap_err(), "E481: No range allowed");
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(command("d 3").arg, Arg::Count(3));
+        assert_eq!(command("d").arg, Arg::None);
+        assert_eq!(parse("d 0").unwrap_err(), "E939: Positive count required");
+        assert_eq!(parse("d x").unwrap_err(), "E488: Trailing characters: x");
+        assert_eq!(parse("q now").unwrap_err(), "E488: Trailing characters: now");
+        assert_eq!(command("m 'a+1").arg, Arg::Address(address(Base::Mark('a'), 1)));
+        assert_eq!(command("t0").arg, Arg::Address(address(Base::Number(0), 0)));
+        assert_eq!(parse("m").unwrap_err(), "E14: Invalid address");
+        assert_eq!(parse("m x").unwrap_err(), "E14: Invalid address");
+        assert_eq!(parse("g").unwrap_err(), "E471: Argument required");
+        assert_eq!(command(">").arg, Arg::None);
+        assert_eq!(command(">> 2").arg, Arg::Shift { times: 2, count: Some(2) });
+        assert_eq!(command("<<<").arg, Arg::Shift { times: 3, count: None });
+        assert_eq!(command("u 3").arg, Arg::Number(3));
+        assert_eq!(parse("u x").unwrap_err(), "E474: Invalid argument: x");
+        assert_eq!(command("sort nu").arg, Arg::Sort(Sort { numeric: true, unique: true, ..Sort::default() }));
+    }
+
+    #[test]
+    fn undo_offsets() {
+        assert_eq!(command("earlier").arg, Arg::None);
+        assert_eq!(command("earlier 3").arg, Arg::Offset(Offset::Steps(3)));
+        assert_eq!(command("later 10m").arg, Arg::Offset(Offset::Time(Duration::from_secs(600))));
+        assert_eq!(command("earlier 2d").arg, Arg::Offset(Offset::Time(Duration::from_secs(2 * 24 * 60 * 60))));
+        assert_eq!(parse("earlier 5é").unwrap_err(), "E475: Invalid argument: 5é");
+        assert_eq!(parse("earlier 999999999999999d").unwrap_err(), "E475: Invalid argument: 999999999999999d");
+        assert_eq!(parse("earlier m").unwrap_err(), "E475: Invalid argument: m");
+        assert_eq!(command("later 9223372036854775807").arg, Arg::Offset(Offset::Steps(isize::MAX)));
+        for bad in ["0", "-3", "-9223372036854775808", "9223372036854775808"] {
+            assert_eq!(parse(&format!("earlier {bad}")).unwrap_err(), format!("E475: Invalid argument: {bad}"));
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+
+/// File types by extension, named the way vim names them.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("cxx", "cpp"),
+    ("hh", "cpp"),
+    ("hpp", "cpp"),
+    ("go", "go"),
+    ("java", "java"),
+    ("py", "python"),
+    ("rb", "ruby"),
+    ("lua", "lua"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("jsx", "javascriptreact"),
+    ("ts", "typescript"),
+    ("tsx", "typescriptreact"),
+    ("html", "html"),
+    ("htm", "html"),
+    ("css", "css"),
+    ("json", "json"),
+    ("toml", "toml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("xml", "xml"),
+    ("md", "markdown"),
+    ("markdown", "markdown"),
+    ("sh", "sh"),
+    ("bash", "bash"),
+    ("zsh", "zsh"),
+    ("fish", "fish"),
+    ("vim", "vim"),
+    ("sql", "sql"),
+    ("txt", "text"),
+];
+
+/// File types by whole file name.
+const NAMES: &[(&str, &str)] = &[
+    ("Makefile", "make"),
+    ("makefile", "make"),
+    ("GNUmakefile", "make"),
+    ("Dockerfile", "dockerfile"),
+    ("CMakeLists.txt", "cmake"),
+    ("Cargo.lock", "toml"),
+    (".bashrc", "bash"),
+    (".zshrc", "zsh"),
+    (".vimrc", "vim"),
+    (".gitconfig", "gitconfig"),
+];
+
+/// Interpreters named on a `#!` line.
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("sh", "sh"),
+    ("bash", "bash"),
+    ("zsh", "zsh"),
+    ("fish", "fish"),
+    ("python", "python"),
+    ("python3", "python"),
+    ("ruby", "ruby"),
+    ("node", "javascript"),
+    ("lua", "lua"),
+    ("perl", "perl"),
+];
+
+/// Guesses the file type from the file's name, or failing that from a
+/// `#!` first line. Returns an empty string if it is unknown.
+pub fn detect(file: &str, first_line: &str) -> &'static str {
+    let path = Path::new(file);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    if let Some((_, ft)) = NAMES.iter().find(|(n, _)| *n == name) {
+        return ft;
+    }
+
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    if let Some((_, ft)) = EXTENSIONS.iter().find(|(e, _)| e.eq_ignore_ascii_case(extension)) {
+        return ft;
+    }
+
+    // `#!/bin/sh`, `#!/usr/bin/env python3 -u`
+    let Some(shebang) = first_line.strip_prefix("#!") else { return "" };
+    let mut words = shebang.split_whitespace();
+    let mut program = words.next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-')).unwrap_or_default();
+    }
+    INTERPRETERS.iter().find(|(p, _)| *p == program).map_or("", |(_, ft)| ft)
+}
@@ -0,0 +1,107 @@
+use ropey::RopeSlice;
+use std::borrow::Cow;
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
+use unicode_width::UnicodeWidthStr;
+
+/// Char index of the grapheme boundary after `char_idx`.
+pub fn next_boundary(slice: RopeSlice, char_idx: usize) -> usize {
+    let len = slice.len_chars();
+    if char_idx >= len {
+        return len;
+    }
+
+    let byte_idx = slice.char_to_byte(char_idx);
+    let (mut chunk, mut chunk_start, _, _) = slice.chunk_at_byte(byte_idx);
+    let mut cursor = GraphemeCursor::new(byte_idx, slice.len_bytes(), true);
+    loop {
+        match cursor.next_boundary(chunk, chunk_start) {
+            Ok(None) => return len,
+            Ok(Some(n)) => return slice.byte_to_char(n),
+            Err(GraphemeIncomplete::NextChunk) => {
+                chunk_start += chunk.len();
+                (chunk, _, _, _) = slice.chunk_at_byte(chunk_start);
+            }
+            Err(GraphemeIncomplete::PreContext(n)) => {
+                let (ctx, ctx_start, _, _) = slice.chunk_at_byte(n - 1);
+                cursor.provide_context(ctx, ctx_start);
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+/// Char index of the grapheme boundary before `char_idx`.
+pub fn prev_boundary(slice: RopeSlice, char_idx: usize) -> usize {
+    if char_idx == 0 {
+        return 0;
+    }
+
+    let byte_idx = slice.char_to_byte(char_idx.min(slice.len_chars()));
+    let (mut chunk, mut chunk_start, _, _) = slice.chunk_at_byte(byte_idx - 1);
+    let mut cursor = GraphemeCursor::new(byte_idx, slice.len_bytes(), true);
+    loop {
+        match cursor.prev_boundary(chunk, chunk_start) {
+            Ok(None) => return 0,
+            Ok(Some(n)) => return slice.byte_to_char(n),
+            Err(GraphemeIncomplete::PrevChunk) => {
+                (chunk, chunk_start, _, _) = slice.chunk_at_byte(chunk_start - 1);
+            }
+            Err(GraphemeIncomplete::PreContext(n)) => {
+                let (ctx, ctx_start, _, _) = slice.chunk_at_byte(n - 1);
+                cursor.provide_context(ctx, ctx_start);
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+/// Iterates over the grapheme clusters of a line.
+pub fn graphemes(slice: RopeSlice<'_>) -> Graphemes<'_> {
+    Graphemes { slice, pos: 0 }
+}
+
+pub struct Graphemes<'a> {
+    slice: RopeSlice<'a>,
+    pos: usize,
+}
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = RopeSlice<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.slice.len_chars() {
+            return None;
+        }
+        let end = next_boundary(self.slice, self.pos);
+        let grapheme = self.slice.slice(self.pos..end);
+        self.pos = end;
+        Some(grapheme)
+    }
+}
+
+/// How a grapheme is drawn. Control characters are shown in caret notation
+/// (`^A`), or as `<9f>` outside of ASCII, the way vim does.
+pub fn display(grapheme: RopeSlice<'_>) -> Cow<'_, str> {
+    let first = grapheme.char(0);
+    if first.is_control() {
+        return match first as u32 {
+            c @ 0..=0x1f => Cow::Owned(format!("^{}", char::from(c as u8 + 0x40))),
+            0x7f => Cow::Borrowed("^?"),
+            c => Cow::Owned(format!("<{c:02x}>")),
+        };
+    }
+
+    match grapheme.as_str() {
+        Some(s) => Cow::Borrowed(s),
+        None => Cow::Owned(grapheme.to_string()),
+    }
+}
+
+/// Number of terminal columns a grapheme takes up when drawn at screen
+/// column `col`. Tabs extend to the next multiple of `tabstop`.
+pub fn width(grapheme: RopeSlice, col: usize, tabstop: usize) -> usize {
+    if grapheme.char(0) == '\t' {
+        return tabstop - col % tabstop;
+    }
+    display(grapheme).width()
+}
@@ -0,0 +1,583 @@
+use ropey::Rope;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const UNDO_MAGIC: &[u8] = b"OXUNDO\x01";
+
+/// A single replacement of `removed` by `inserted` at char index `pos`.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub pos: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+impl Change {
+    fn apply(&self, text: &mut Rope) {
+        let end = self.pos + self.removed.chars().count();
+        text.remove(self.pos..end);
+        text.insert(self.pos, &self.inserted);
+    }
+
+    fn revert(&self, text: &mut Rope) {
+        let end = self.pos + self.inserted.chars().count();
+        text.remove(self.pos..end);
+        text.insert(self.pos, &self.removed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Revision {
+    pub parent: usize,
+    /// The child that was most recently visited, followed by redo.
+    pub last_child: Option<usize>,
+    pub changes: Vec<Change>,
+    pub timestamp: SystemTime,
+}
+
+/// Undo tree. Revision 0 is the text as it was loaded; every other revision
+/// is a group of changes applied on top of its parent. Revision numbers are
+/// assigned in creation order, which is what `g-`/`g+` walk through.
+#[derive(Debug)]
+pub struct History {
+    revisions: Vec<Revision>,
+    current: usize,
+    pending: Vec<Change>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            revisions: vec![Revision {
+                parent: 0,
+                last_child: None,
+                changes: Vec::new(),
+                timestamp: SystemTime::now(),
+            }],
+            current: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.revisions.len()
+    }
+
+    /// Whether there are changes not yet committed as a revision.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revisions.len() == 1 && self.pending.is_empty()
+    }
+
+    /// Records a change that has already been applied to the text. Typing
+    /// runs of characters are merged into a single change.
+    pub fn record(&mut self, change: Change) {
+        if let Some(last) = self.pending.last_mut() {
+            if change.removed.is_empty()
+                && last.removed.is_empty()
+                && last.pos + last.inserted.chars().count() == change.pos
+            {
+                last.inserted.push_str(&change.inserted);
+                return;
+            }
+        }
+        self.pending.push(change);
+    }
+
+    /// Closes the pending group of changes as a new revision.
+    pub fn commit(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let id = self.revisions.len();
+        self.revisions.push(Revision {
+            parent: self.current,
+            last_child: None,
+            changes: std::mem::take(&mut self.pending),
+            timestamp: SystemTime::now(),
+        });
+        self.revisions[self.current].last_child = Some(id);
+        self.current = id;
+    }
+
+    /// Undoes the current revision. Returns the char index where the
+    /// text changed.
+    pub fn undo(&mut self, text: &mut Rope) -> Option<usize> {
+        self.commit();
+        if self.current == 0 {
+            return None;
+        }
+
+        let rev = &self.revisions[self.current];
+        for change in rev.changes.iter().rev() {
+            change.revert(text);
+        }
+        let pos = rev.changes.iter().map(|c| c.pos).min();
+        let parent = rev.parent;
+        self.revisions[parent].last_child = Some(self.current);
+        self.current = parent;
+        pos
+    }
+
+    /// Redoes the most recently undone child of the current revision.
+    pub fn redo(&mut self, text: &mut Rope) -> Option<usize> {
+        self.commit();
+        let child = self.revisions[self.current].last_child?;
+        let rev = &self.revisions[child];
+        for change in &rev.changes {
+            change.apply(text);
+        }
+        let pos = rev.changes.iter().map(|c| c.pos).min();
+        self.current = child;
+        pos
+    }
+
+    /// Moves to an arbitrary revision, undoing up to the common ancestor
+    /// and redoing down the target's branch.
+    pub fn jump_to(&mut self, target: usize, text: &mut Rope) -> Option<usize> {
+        self.commit();
+        if target >= self.revisions.len() || target == self.current {
+            return None;
+        }
+
+        let ancestors = self.ancestors(target);
+        let mut pos = None;
+        while !ancestors.contains(&self.current) {
+            pos = self.undo(text).or(pos);
+        }
+
+        let depth = ancestors.iter().position(|&r| r == self.current).unwrap();
+        for &rev in ancestors[..depth].iter().rev() {
+            self.revisions[self.current].last_child = Some(rev);
+            pos = self.redo(text).or(pos);
+        }
+        pos
+    }
+
+    /// Steps `count` revisions back (`g-`) or forward (`g+`) in creation
+    /// order.
+    pub fn step(&mut self, count: isize, text: &mut Rope) -> Option<usize> {
+        self.commit();
+        let last = self.revisions.len() as isize - 1;
+        let target = (self.current as isize).saturating_add(count).clamp(0, last) as usize;
+        self.jump_to(target, text)
+    }
+
+    /// Moves to the newest revision at least `duration` older (or, going
+    /// forward, at most `duration` newer) than the current one.
+    pub fn travel(&mut self, duration: Duration, forward: bool, text: &mut Rope) -> Option<usize> {
+        self.commit();
+        let now = self.revisions[self.current].timestamp;
+        let target = if forward {
+            let limit = now.checked_add(duration);
+            self.revisions
+                .iter()
+                .rposition(|r| limit.is_none_or(|limit| r.timestamp <= limit))
+                .unwrap_or(self.current)
+                .max(self.current)
+        } else {
+            let limit = now.checked_sub(duration).unwrap_or(SystemTime::UNIX_EPOCH);
+            self.revisions[1..]
+                .iter()
+                .rposition(|r| r.timestamp <= limit)
+                .map(|i| i + 1)
+                .unwrap_or(0)
+                .min(self.current)
+        };
+        self.jump_to(target, text)
+    }
+
+    /// Revisions from `rev` up to and including the root.
+    fn ancestors(&self, mut rev: usize) -> Vec<usize> {
+        let mut path = vec![rev];
+        while rev != 0 {
+            rev = self.revisions[rev].parent;
+            path.push(rev);
+        }
+        path
+    }
+}
+
+impl History {
+    /// Writes the undo tree to `path`. `hash` identifies the text the tree's
+    /// current revision corresponds to, see [`content_hash`].
+    pub fn save(&mut self, path: &Path, hash: u64) -> io::Result<()> {
+        self.commit();
+
+        let mut out = UNDO_MAGIC.to_vec();
+        put_u64(&mut out, hash);
+        put_u64(&mut out, self.current as u64);
+        put_u64(&mut out, self.revisions.len() as u64);
+        for rev in &self.revisions {
+            let time = rev.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            put_u64(&mut out, rev.parent as u64);
+            put_u64(&mut out, rev.last_child.map_or(0, |c| c as u64 + 1));
+            put_u64(&mut out, time.as_secs());
+            put_u64(&mut out, time.subsec_nanos() as u64);
+            put_u64(&mut out, rev.changes.len() as u64);
+            for change in &rev.changes {
+                put_u64(&mut out, change.pos as u64);
+                put_str(&mut out, &change.removed);
+                put_str(&mut out, &change.inserted);
+            }
+        }
+
+        write_state_file(path, |file| file.write_all(&out))
+    }
+
+    /// Reads an undo tree written by [`History::save`]. Returns `None` when
+    /// there is no undo file, or when it was written for different text
+    /// than `hash` describes, e.g. because the file was changed outside
+    /// the editor.
+    pub fn load(path: &Path, hash: u64, text: &Rope) -> io::Result<Option<Self>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut r = Reader { data: &data };
+        if r.take(UNDO_MAGIC.len())? != UNDO_MAGIC {
+            return Err(invalid("not an undo file"));
+        }
+        if r.u64()? != hash {
+            return Ok(None);
+        }
+
+        let current = r.usize()?;
+        let count = r.usize()?;
+        let mut revisions = Vec::new();
+        for id in 0..count {
+            let parent = r.usize()?;
+            let last_child = r.usize()?.checked_sub(1);
+            let (secs, nanos) = (r.u64()?, r.u64()?);
+            let timestamp = u32::try_from(nanos)
+                .ok()
+                .filter(|&nanos| nanos < 1_000_000_000)
+                .and_then(|nanos| SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+                .ok_or_else(|| invalid("malformed undo file"))?;
+            let changes = (0..r.usize()?)
+                .map(|_| Ok(Change { pos: r.usize()?, removed: r.string()?, inserted: r.string()? }))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            if (id > 0 && parent >= id) || last_child.is_some_and(|c| c <= id || c >= count) {
+                return Err(invalid("malformed undo tree"));
+            }
+            revisions.push(Revision {
+                parent,
+                last_child,
+                changes,
+                timestamp,
+            });
+        }
+
+        if revisions.is_empty() || current >= revisions.len() {
+            return Err(invalid("malformed undo tree"));
+        }
+
+        let history = Self { revisions, current, pending: Vec::new() };
+        if !history.fits(text) {
+            return Err(invalid("undo tree does not match the text"));
+        }
+        Ok(Some(history))
+    }
+
+    /// Checks that every change in the tree stays within the bounds of the
+    /// text it applies to, so a bad undo file can't corrupt the buffer.
+    fn fits(&self, text: &Rope) -> bool {
+        let delta = |rev: &Revision| {
+            rev.changes.iter().fold(0isize, |len, c| {
+                len + c.inserted.chars().count() as isize - c.removed.chars().count() as isize
+            })
+        };
+
+        let mut root_len = text.len_chars() as isize;
+        for rev in self.ancestors(self.current) {
+            root_len -= delta(&self.revisions[rev]);
+        }
+        if root_len < 0 {
+            return false;
+        }
+
+        let mut lens = vec![root_len];
+        for rev in &self.revisions[1..] {
+            let mut len = lens[rev.parent];
+            for change in &rev.changes {
+                let removed = change.removed.chars().count() as isize;
+                if change.pos as isize + removed > len {
+                    return false;
+                }
+                len += change.inserted.chars().count() as isize - removed;
+            }
+            lens.push(len);
+        }
+        true
+    }
+}
+
+/// Where the undo history for `file` is kept, see [`state_file`].
+pub fn undo_file(file: &str) -> Option<PathBuf> {
+    state_file("undo", file)
+}
+
+/// A per-file path under `$XDG_STATE_HOME/oxidate/{kind}`: the file's
+/// absolute path with `/` replaced by `%`.
+pub fn state_file(kind: &str, file: &str) -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+    let path = std::path::absolute(file).ok()?;
+    let name = path.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "%");
+    Some(state.join("oxidate").join(kind).join(name))
+}
+
+/// Replaces the state file at `path` with what `write` puts in it. The
+/// file is only readable by its owner, and is never left half written.
+pub fn write_state_file(path: &Path, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let result = (|| {
+        let mut out = io::BufWriter::new(options.open(&temp)?);
+        write(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// FNV-1a hash of the text, used to tell whether an undo file still matches
+/// the file on disk.
+pub fn content_hash(text: &Rope) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for chunk in text.chunks() {
+        for byte in chunk.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u64(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if n > self.data.len() {
+            return Err(invalid("truncated undo file"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid("malformed undo file"))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("malformed undo file"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies and records an edit, as `Buffer` does.
+    fn edit(history: &mut History, text: &mut Rope, pos: usize, removed: usize, inserted: &str) {
+        let change = Change { pos, removed: text.slice(pos..pos + removed).to_string(), inserted: inserted.to_string() };
+        change.apply(text);
+        history.record(change);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let (mut history, mut text) = (History::new(), Rope::from_str("abc"));
+        edit(&mut history, &mut text, 3, 0, "d");
+        history.commit();
+        edit(&mut history, &mut text, 0, 1, "A");
+        history.commit();
+        assert_eq!(text, "Abcd");
+
+        assert_eq!(history.undo(&mut text), Some(0));
+        assert_eq!(text, "abcd");
+        assert_eq!(history.undo(&mut text), Some(3));
+        assert_eq!(text, "abc");
+        assert_eq!(history.undo(&mut text), None);
+
+        assert_eq!(history.redo(&mut text), Some(3));
+        assert_eq!(history.redo(&mut text), Some(0));
+        assert_eq!(history.redo(&mut text), None);
+        assert_eq!(text, "Abcd");
+    }
+
+    #[test]
+    fn typing_is_one_change() {
+        let (mut history, mut text) = (History::new(), Rope::new());
+        for (i, c) in ["a", "b", "c"].into_iter().enumerate() {
+            edit(&mut history, &mut text, i, 0, c);
+        }
+        history.commit();
+        assert_eq!(history.revisions[1].changes.len(), 1);
+        history.undo(&mut text);
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn uncommitted_changes_are_undone_together() {
+        let (mut history, mut text) = (History::new(), Rope::from_str("ab"));
+        edit(&mut history, &mut text, 0, 1, "x");
+        edit(&mut history, &mut text, 1, 1, "y");
+        assert!(history.has_pending());
+        history.undo(&mut text);
+        assert_eq!(text, "ab");
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn branches() {
+        let (mut history, mut text) = (History::new(), Rope::from_str("a"));
+        edit(&mut history, &mut text, 1, 0, "b");
+        history.commit();
+        history.undo(&mut text);
+        edit(&mut history, &mut text, 1, 0, "c");
+        history.commit();
+        assert_eq!((history.current(), text.to_string()), (2, "ac".to_string()));
+
+        // Redo follows the branch last visited.
+        history.undo(&mut text);
+        history.redo(&mut text);
+        assert_eq!(text, "ac");
+
+        // `g-` and `:undo 1` reach the other branch.
+        history.step(-1, &mut text);
+        assert_eq!((history.current(), text.to_string()), (1, "ab".to_string()));
+        history.jump_to(2, &mut text);
+        assert_eq!(text, "ac");
+        history.jump_to(0, &mut text);
+        assert_eq!(text, "a");
+        history.step(5, &mut text);
+        assert_eq!((history.current(), text.to_string()), (2, "ac".to_string()));
+        history.step(isize::MIN, &mut text);
+        assert_eq!(history.current(), 0);
+        history.step(isize::MAX, &mut text);
+        assert_eq!(history.current(), 2);
+    }
+
+    #[test]
+    fn travel() {
+        let (mut history, mut text) = (History::new(), Rope::new());
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        history.revisions[0].timestamp = start;
+        for (i, minutes) in [1, 2, 10].into_iter().enumerate() {
+            edit(&mut history, &mut text, i, 0, "x");
+            history.commit();
+            history.revisions[i + 1].timestamp = start + Duration::from_secs(minutes * 60);
+        }
+
+        history.travel(Duration::from_secs(60), false, &mut text);
+        assert_eq!(history.current(), 2);
+        history.travel(Duration::from_secs(120), false, &mut text);
+        assert_eq!(history.current(), 0);
+        history.travel(Duration::from_secs(150), true, &mut text);
+        assert_eq!(history.current(), 2);
+        history.travel(Duration::MAX, true, &mut text);
+        assert_eq!((history.current(), text.to_string()), (3, "xxx".to_string()));
+        history.travel(Duration::MAX, false, &mut text);
+        assert_eq!((history.current(), text.to_string()), (0, String::new()));
+    }
+
+    #[test]
+    fn saved_privately_and_loaded_back() {
+        let (mut history, mut text) = (History::new(), Rope::from_str("a"));
+        edit(&mut history, &mut text, 1, 0, "b");
+        let dir = std::env::temp_dir().join(format!("oxidate-test-{}-undo", std::process::id()));
+        let path = dir.join("file");
+        history.save(&path, content_hash(&text)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let loaded = History::load(&path, content_hash(&text), &text);
+        let stale = History::load(&path, content_hash(&Rope::from_str("a")), &text);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut loaded = loaded.unwrap().unwrap();
+        assert!(stale.unwrap().is_none());
+        assert_eq!((loaded.current(), loaded.len()), (1, 2));
+        loaded.undo(&mut text);
+        assert_eq!(text, "a");
+    }
+
+    #[test]
+    fn bad_timestamps_are_invalid() {
+        let (mut history, text) = (History::new(), Rope::from_str("a"));
+        let path = std::env::temp_dir().join(format!("oxidate-test-{}-timestamp", std::process::id()));
+        history.save(&path, content_hash(&text)).unwrap();
+        // The first revision's seconds, then its nanoseconds, follow the
+        // magic, hash, current revision, count, parent and last child.
+        let at = UNDO_MAGIC.len() + 5 * 8;
+        let mut results = Vec::new();
+        for (secs, nanos) in [(u64::MAX, 0), (0, 1_000_000_000), (0, u64::MAX)] {
+            let mut data = fs::read(&path).unwrap();
+            data[at..at + 8].copy_from_slice(&secs.to_le_bytes());
+            data[at + 8..at + 16].copy_from_slice(&nanos.to_le_bytes());
+            fs::write(&path, data).unwrap();
+            results.push(History::load(&path, content_hash(&text), &text).map(|_| ()));
+        }
+        fs::remove_file(&path).unwrap();
+        for result in results {
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+pub mod buffer;
+pub mod editor;
+pub mod ex;
+pub mod filetype;
+pub mod grapheme;
+pub mod history;
+pub mod message;
+pub mod operator;
+pub mod options;
+pub mod save;
+pub mod search;
+pub mod sort;
+pub mod statusline;
+pub mod substitute;
+pub mod swap;
+pub mod textobject;
+pub mod word;
+pub mod wrap;
@@ -1,22 +1,46 @@
 use crossterm::{terminal, ExecutableCommand};
-use editor::Editor;
-use buffer::Buffer;
+use oxidate::editor::Editor;
+use oxidate::buffer::Buffer;
+use oxidate::message::{Level, Message};
+use oxidate::swap;
 use std::{io::stdout, io, panic};
 
-mod editor;
-mod buffer;
-
 fn main() -> io::Result<()> {
     let file = std::env::args().nth(1);
-    let buffer = Buffer::from_file(file);
+    let (buffer, message) = match Buffer::from_file(file.clone()) {
+        Ok(buffer) => {
+            let message = buffer.new_file.then(|| Message {
+                level: Level::Info,
+                text: format!("{:?} [New]", file.as_deref().unwrap_or_default()),
+            });
+            (buffer, message)
+        }
+        Err(e) => {
+            // Keep the name but don't let a save clobber a file we couldn't read.
+            let mut buffer = Buffer::new(file);
+            buffer.readonly = true;
+            buffer.unreadable = true;
+            (buffer, Some(Message { level: Level::Error, text: e.to_string() }))
+        }
+    };
     let mut editor = Editor::new(buffer)?;
+    if let Some(message) = message {
+        editor.show(message);
+    }
 
     panic::set_hook(Box::new(|info| {
+        let swap = swap::flush();
         _ = stdout().execute(terminal::LeaveAlternateScreen);
         _ = terminal::disable_raw_mode();
         eprintln!("{}", info);
+        if let Some(swap) = swap {
+            eprintln!("Unsaved changes were written to {}", swap.display());
+        }
     }));
 
+    if !editor.check_swap()? {
+        return editor.cleanup();
+    }
     editor.run()?;
     editor.cleanup()
 }
@@ -0,0 +1,60 @@
+use crossterm::style::{Color, ContentStyle, Stylize};
+use std::collections::VecDeque;
+
+/// Number of messages `:messages` can scroll back through.
+const LOG_SIZE: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Feedback from a command, shown on the command line row.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub level: Level,
+    pub text: String,
+}
+
+impl Message {
+    pub fn style(&self) -> ContentStyle {
+        match self.level {
+            Level::Info => ContentStyle::new(),
+            Level::Warning => ContentStyle::new().with(Color::Yellow).bold(),
+            Level::Error => ContentStyle::new().with(Color::White).on(Color::DarkRed).bold(),
+        }
+    }
+}
+
+/// The most recent messages, oldest first.
+#[derive(Default)]
+pub struct Log {
+    messages: VecDeque<Message>,
+}
+
+impl Log {
+    pub fn push(&mut self, message: Message) {
+        if self.messages.len() == LOG_SIZE {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
@@ -0,0 +1,166 @@
+use std::ops::RangeInclusive;
+
+/// A command that works on the text a motion moves over, as `d` in `dw`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    Lowercase,
+    Uppercase,
+    ToggleCase,
+    Indent,
+}
+
+impl Operator {
+    /// The operator typed as `key`, after a `g` for `gu`, `gU` and `g~`.
+    pub fn from_key(g: bool, key: char) -> Option<Self> {
+        Some(match (g, key) {
+            (false, 'd') => Operator::Delete,
+            (false, 'c') => Operator::Change,
+            (false, 'y') => Operator::Yank,
+            (false, '>') => Operator::ShiftRight,
+            (false, '<') => Operator::ShiftLeft,
+            (false, '=') => Operator::Indent,
+            (true, 'u') => Operator::Lowercase,
+            (true, 'U') => Operator::Uppercase,
+            (true, '~') => Operator::ToggleCase,
+            _ => return None,
+        })
+    }
+
+    /// The last key of the operator. Typing it again works on whole lines,
+    /// as in `dd` or `guu`.
+    pub fn key(self) -> char {
+        match self {
+            Operator::Delete => 'd',
+            Operator::Change => 'c',
+            Operator::Yank => 'y',
+            Operator::ShiftRight => '>',
+            Operator::ShiftLeft => '<',
+            Operator::Indent => '=',
+            Operator::Lowercase => 'u',
+            Operator::Uppercase => 'U',
+            Operator::ToggleCase => '~',
+        }
+    }
+
+    /// Maps the text of a case operator.
+    pub fn convert_case(self, text: &str) -> String {
+        match self {
+            Operator::Lowercase => text.to_lowercase(),
+            Operator::Uppercase => text.to_uppercase(),
+            Operator::ToggleCase => text
+                .chars()
+                .map(|c| if c.is_uppercase() { c.to_lowercase().to_string() } else { c.to_uppercase().to_string() })
+                .collect(),
+            _ => text.to_string(),
+        }
+    }
+}
+
+/// The text an operator works on, from `(column, line)` `start` to `end`.
+/// A charwise range ends before `end`; a linewise one covers every line
+/// from `start.1` to `end.1` whole, whatever the columns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub linewise: bool,
+}
+
+impl Range {
+    pub fn lines(&self) -> RangeInclusive<usize> {
+        self.start.1..=self.end.1
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.end.1 - self.start.1 + 1
+    }
+
+    pub fn contains(&self, (x, y): (usize, usize)) -> bool {
+        if self.linewise {
+            return self.lines().contains(&y);
+        }
+        (self.start.1, self.start.0) <= (y, x) && (y, x) < (self.end.1, self.end.0)
+    }
+}
+
+/// Text that was deleted or yanked, for putting back with `p`.
+#[derive(Debug, Clone, Default)]
+pub struct Register {
+    /// Linewise text ends in a line break.
+    pub text: String,
+    pub linewise: bool,
+}
+
+/// How much the line after `line` should be indented relative to it: one
+/// level per bracket left open, not counting closing brackets at the start
+/// of `line`, which are dedented on the line itself. Brackets in double
+/// quoted strings and in character literals like `'{'` don't count, but
+/// those in comments do.
+pub fn indent_change(line: &str) -> isize {
+    let trimmed = line.trim_start();
+    let leading = trimmed.chars().take_while(|c| matches!(c, ')' | ']' | '}')).count() as isize;
+    let mut depth = 0;
+    let mut chars = trimmed.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => _ = chars.next(),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            // Only a quote closed one char later, or two after a
+            // backslash, so that a lone `'` as in `<'a>` isn't a string.
+            '\'' => {
+                let mut ahead = chars.clone();
+                let closed = match ahead.next() {
+                    Some('\\') => ahead.next().is_some(),
+                    c => c.is_some(),
+                } && ahead.next() == Some('\'');
+                if closed {
+                    chars = ahead;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth + leading
+}
+
+/// Whether `line` starts by closing a bracket, and is indented one level
+/// less than the line before it for that.
+pub fn starts_with_closer(line: &str) -> bool {
+    matches!(line.trim_start().chars().next(), Some(')' | ']' | '}'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indent_changes() {
+        assert_eq!(indent_change("fn main() {"), 1);
+        assert_eq!(indent_change("    foo(bar, [baz"), 2);
+        assert_eq!(indent_change("}"), 0);
+        assert_eq!(indent_change("}) else {"), 1);
+        assert_eq!(indent_change("    x)"), -1);
+    }
+
+    #[test]
+    fn brackets_in_quotes_dont_count() {
+        assert_eq!(indent_change(r#"let s = "{";"#), 0);
+        assert_eq!(indent_change(r#"let s = "a \" {" + f(x"#), 1);
+        assert_eq!(indent_change("if c == '{' || c == '\\'' {"), 1);
+        assert_eq!(indent_change("fn f<'a>(x: &'a str) {"), 1);
+    }
+}
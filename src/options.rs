@@ -0,0 +1,249 @@
+/// Settings changed with `:set`. Names and defaults follow vim.
+pub struct Options {
+    pub tabstop: usize,
+    pub shiftwidth: usize,
+    pub softtabstop: isize,
+    pub expandtab: bool,
+    pub backup: bool,
+    /// Lines kept visible above and below the cursor.
+    pub scrolloff: usize,
+    /// Lines scrolled by `Ctrl-d` and `Ctrl-u`; zero means half a window.
+    pub scroll: usize,
+    /// Columns kept visible left and right of the cursor.
+    pub sidescrolloff: usize,
+    /// Lines longer than the window continue on the next screen row.
+    pub wrap: bool,
+    /// Shown at the start of rows that continue a wrapped line.
+    pub showbreak: String,
+    pub ignorecase: bool,
+    /// Searches with a capital letter in them match case anyway.
+    pub smartcase: bool,
+    /// Shows where a search pattern matches while it is being typed.
+    pub incsearch: bool,
+    /// Searches go on from the other end of the buffer.
+    pub wrapscan: bool,
+    /// Highlights every match of the last search.
+    pub hlsearch: bool,
+    /// Milliseconds without typing before the swap file is written.
+    pub updatetime: usize,
+    /// Changes after which the swap file is written; zero turns swap files
+    /// off.
+    pub updatecount: usize,
+    /// Format of the status line, see [`crate::statusline::render`].
+    pub statusline: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            tabstop: 8,
+            shiftwidth: 8,
+            softtabstop: 0,
+            expandtab: false,
+            backup: false,
+            scrolloff: 0,
+            scroll: 0,
+            sidescrolloff: 0,
+            wrap: false,
+            showbreak: String::new(),
+            ignorecase: false,
+            smartcase: false,
+            incsearch: true,
+            wrapscan: true,
+            hlsearch: true,
+            updatetime: 4000,
+            updatecount: 200,
+            statusline: " %f %m%r%= %y %{&fenc} %{&ff}  %l:%c  %p%% ".to_string(),
+        }
+    }
+}
+
+pub enum Value<'a> {
+    Bool(&'a mut bool),
+    Number(&'a mut usize),
+    Int(&'a mut isize),
+    Str(&'a mut dyn StrValue),
+}
+
+/// An option set with `name=value` that isn't a number.
+pub trait StrValue {
+    fn get(&self) -> String;
+    /// Returns `false` if `value` isn't valid for this option.
+    fn set(&mut self, value: &str) -> bool;
+}
+
+impl StrValue for String {
+    fn get(&self) -> String {
+        self.clone()
+    }
+
+    fn set(&mut self, value: &str) -> bool {
+        *self = value.to_string();
+        true
+    }
+}
+
+/// Something that owns options, looked up by full or short name.
+pub trait Settings {
+    fn lookup(&mut self, name: &str) -> Option<(&'static str, Value<'_>)>;
+}
+
+impl<T: Settings + ?Sized> Settings for &mut T {
+    fn lookup(&mut self, name: &str) -> Option<(&'static str, Value<'_>)> {
+        (**self).lookup(name)
+    }
+}
+
+/// Global options together with buffer-local ones.
+impl<A: Settings, B: Settings> Settings for (A, B) {
+    fn lookup(&mut self, name: &str) -> Option<(&'static str, Value<'_>)> {
+        if self.0.lookup(name).is_some() {
+            return self.0.lookup(name);
+        }
+        self.1.lookup(name)
+    }
+}
+
+impl Settings for Options {
+    fn lookup(&mut self, name: &str) -> Option<(&'static str, Value<'_>)> {
+        Some(match name {
+            "tabstop" | "ts" => ("tabstop", Value::Number(&mut self.tabstop)),
+            "shiftwidth" | "sw" => ("shiftwidth", Value::Number(&mut self.shiftwidth)),
+            "softtabstop" | "sts" => ("softtabstop", Value::Int(&mut self.softtabstop)),
+            "expandtab" | "et" => ("expandtab", Value::Bool(&mut self.expandtab)),
+            "backup" | "bk" => ("backup", Value::Bool(&mut self.backup)),
+            "scrolloff" | "so" => ("scrolloff", Value::Number(&mut self.scrolloff)),
+            "scroll" | "scr" => ("scroll", Value::Number(&mut self.scroll)),
+            "sidescrolloff" | "siso" => ("sidescrolloff", Value::Number(&mut self.sidescrolloff)),
+            "wrap" => ("wrap", Value::Bool(&mut self.wrap)),
+            "showbreak" | "sbr" => ("showbreak", Value::Str(&mut self.showbreak)),
+            "ignorecase" | "ic" => ("ignorecase", Value::Bool(&mut self.ignorecase)),
+            "smartcase" | "scs" => ("smartcase", Value::Bool(&mut self.smartcase)),
+            "incsearch" | "is" => ("incsearch", Value::Bool(&mut self.incsearch)),
+            "wrapscan" | "ws" => ("wrapscan", Value::Bool(&mut self.wrapscan)),
+            "hlsearch" | "hls" => ("hlsearch", Value::Bool(&mut self.hlsearch)),
+            "updatetime" | "ut" => ("updatetime", Value::Number(&mut self.updatetime)),
+            "updatecount" | "uc" => ("updatecount", Value::Number(&mut self.updatecount)),
+            "statusline" | "stl" => ("statusline", Value::Str(&mut self.statusline)),
+            _ => return None,
+        })
+    }
+}
+
+impl Options {
+    /// Effective indent width: `shiftwidth`, or `tabstop` when it is zero.
+    pub fn shiftwidth(&self) -> usize {
+        if self.shiftwidth == 0 { self.tabstop } else { self.shiftwidth }
+    }
+
+    /// Whitespace that indents a line by `width` columns: tabs as far as
+    /// they go, unless `expandtab` is set.
+    pub fn indent(&self, width: usize) -> String {
+        if self.expandtab {
+            return " ".repeat(width);
+        }
+        let ts = self.tabstop;
+        "\t".repeat(width / ts) + &" ".repeat(width % ts)
+    }
+
+    /// Effective soft tab width: `softtabstop`, or `shiftwidth` when it is
+    /// negative. Zero means soft tabs are off.
+    pub fn softtabstop(&self) -> usize {
+        if self.softtabstop < 0 { self.shiftwidth() } else { self.softtabstop as usize }
+    }
+}
+
+/// An option's value the way `&name` gives it in vim: `1` or `0` for
+/// booleans.
+pub fn get(settings: &mut impl Settings, name: &str) -> Option<String> {
+    Some(match settings.lookup(name)?.1 {
+        Value::Bool(b) => (*b as u8).to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::Str(s) => s.get(),
+    })
+}
+
+/// Splits the arguments of `:set` at whitespace, except where it is
+/// escaped with a backslash as in `stl=%f\ %m`.
+pub fn split_args(line: &str) -> Vec<String> {
+    let (mut args, mut arg) = (Vec::new(), String::new());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next.is_whitespace() || next == '\\' => arg.push(next),
+                Some(next) => arg.extend(['\\', next]),
+                None => arg.push('\\'),
+            },
+            c if c.is_whitespace() => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+/// Applies a single `:set` argument such as `ts=4`, `et`, `noet`, `invet`
+/// or `ts?`. Returns a message to show for queries.
+pub fn set(settings: &mut impl Settings, arg: &str) -> Result<Option<String>, String> {
+    let unknown = || format!("E518: Unknown option: {arg}");
+
+    if let Some((name, value)) = arg.split_once('=') {
+        let (full, option) = settings.lookup(name).ok_or_else(unknown)?;
+        let number = || format!("E521: Number required after =: {arg}");
+        let invalid = || format!("E474: Invalid argument: {arg}");
+        match option {
+            Value::Number(n) => {
+                let value: usize = value.parse().map_err(|_| number())?;
+                if value == 0 && full == "tabstop" {
+                    return Err(format!("E487: Argument must be positive: {arg}"));
+                }
+                *n = value;
+            }
+            Value::Int(n) => *n = value.parse().map_err(|_| number())?,
+            Value::Str(s) => {
+                if !s.set(value) {
+                    return Err(invalid());
+                }
+            }
+            Value::Bool(_) => return Err(invalid()),
+        }
+        return Ok(None);
+    }
+
+    if let Some(name) = arg.strip_suffix('?') {
+        let (full, option) = settings.lookup(name).ok_or_else(unknown)?;
+        return Ok(Some(match option {
+            Value::Bool(b) => format!("{}{full}", if *b { "  " } else { "no" }),
+            Value::Number(n) => format!("  {full}={n}"),
+            Value::Int(n) => format!("  {full}={n}"),
+            Value::Str(s) => format!("  {full}={}", s.get()),
+        }));
+    }
+
+    // `noet`, `invet` and `et!`, unless the prefix is part of the name.
+    let (name, value) = match arg.strip_suffix('!') {
+        Some(name) => (name, None),
+        None if settings.lookup(arg).is_some() => (arg, Some(true)),
+        None => match (arg.strip_prefix("no"), arg.strip_prefix("inv")) {
+            (Some(name), _) => (name, Some(false)),
+            (_, Some(name)) => (name, None),
+            _ => (arg, Some(true)),
+        },
+    };
+
+    match settings.lookup(name).ok_or_else(unknown)?.1 {
+        Value::Bool(b) => *b = value.unwrap_or(!*b),
+        // Naming a value option shows its value, like `ts?`.
+        _ if name == arg => return set(settings, &format!("{arg}?")),
+        _ => return Err(format!("E474: Invalid argument: {arg}")),
+    }
+    Ok(None)
+}
@@ -0,0 +1,180 @@
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Writes a file without ever leaving it truncated: the contents go to a
+/// temporary file in the same directory, which is synced and renamed over
+/// the original. Symlinks are followed so the link itself is kept, and the
+/// original's permissions and owner carry over. With `backup`, the previous
+/// contents are kept as `file~`.
+///
+/// Files with several hard links, or whose owner can't be kept, are written
+/// in place instead, with a copy of the original kept until the write has
+/// been synced.
+pub fn write(
+    path: &Path,
+    backup: bool,
+    contents: impl FnOnce(&mut File) -> io::Result<usize>,
+) -> io::Result<usize> {
+    let target = resolve_symlinks(path);
+    let metadata = match fs::metadata(&target) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let backup_path = sibling(&target, "", "~");
+    if backup && metadata.is_some() {
+        fs::copy(&target, &backup_path)?;
+    }
+
+    let Some(metadata) = metadata else {
+        return write_renamed(&target, None, contents).map_err(|(e, _)| e);
+    };
+    if hard_links(&metadata) > 1 {
+        return write_in_place(&target, backup.then_some(backup_path), contents);
+    }
+
+    match write_renamed(&target, Some(&metadata), contents) {
+        Ok(bytes) => Ok(bytes),
+        Err((_, Some(contents))) => write_in_place(&target, backup.then_some(backup_path), contents),
+        Err((e, None)) => Err(e),
+    }
+}
+
+/// A failed write, with the contents callback if it was never called.
+type RenameError<F> = (io::Error, Option<F>);
+
+/// Writes through a temporary file renamed into place. If the original's
+/// owner can't be kept, gives `contents` back so the caller can write in
+/// place instead.
+fn write_renamed<F>(target: &Path, metadata: Option<&Metadata>, contents: F) -> Result<usize, RenameError<F>>
+where
+    F: FnOnce(&mut File) -> io::Result<usize>,
+{
+    let (mut file, temp) = create_temp(target).map_err(|e| (e, None))?;
+    if let Some(metadata) = metadata {
+        if let Err(e) = copy_owner(&file, metadata) {
+            drop(file);
+            let _ = fs::remove_file(&temp);
+            return Err((e, Some(contents)));
+        }
+    }
+
+    let result = fill_and_rename(&mut file, &temp, target, metadata, contents);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|e| (e, None))
+}
+
+fn fill_and_rename(
+    file: &mut File,
+    temp: &Path,
+    target: &Path,
+    metadata: Option<&Metadata>,
+    contents: impl FnOnce(&mut File) -> io::Result<usize>,
+) -> io::Result<usize> {
+    if let Some(metadata) = metadata {
+        file.set_permissions(metadata.permissions())?;
+    }
+    let bytes = contents(file)?;
+    file.sync_all()?;
+    fs::rename(temp, target)?;
+    sync_dir(target);
+    Ok(bytes)
+}
+
+/// Overwrites `target` directly, keeping a copy of it until the new
+/// contents are on disk. The copy is `backup` if one was made already.
+fn write_in_place(
+    target: &Path,
+    backup: Option<PathBuf>,
+    contents: impl FnOnce(&mut File) -> io::Result<usize>,
+) -> io::Result<usize> {
+    let safety = match backup {
+        Some(_) => None,
+        None => {
+            let copy = sibling(target, ".", &format!(".{}.orig~", std::process::id()));
+            fs::copy(target, &copy)?;
+            Some(copy)
+        }
+    };
+
+    let mut file = OpenOptions::new().write(true).truncate(true).open(target)?;
+    let bytes = contents(&mut file)?;
+    file.sync_all()?;
+
+    if let Some(copy) = safety {
+        let _ = fs::remove_file(copy);
+    }
+    Ok(bytes)
+}
+
+fn create_temp(target: &Path) -> io::Result<(File, PathBuf)> {
+    for n in 0.. {
+        let temp = sibling(target, ".", &format!(".{}.{n}.tmp~", std::process::id()));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && n < 100 => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// `dir/{prefix}{name}{suffix}` for `dir/name`.
+fn sibling(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!("{prefix}{name}{suffix}"))
+}
+
+/// Follows symlinks to the file they point at, even if it doesn't exist yet.
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    // Give up on loops the way the OS does.
+    for _ in 0..40 {
+        match fs::read_link(&path) {
+            Ok(link) => path = path.parent().unwrap_or(Path::new("")).join(link),
+            Err(_) => break,
+        }
+    }
+    path
+}
+
+/// Makes the rename durable. Not every filesystem supports this, so
+/// failures are ignored.
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(unix)]
+fn hard_links(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(metadata)
+}
+
+#[cfg(not(unix))]
+fn hard_links(_: &Metadata) -> u64 {
+    1
+}
+
+#[cfg(unix)]
+fn copy_owner(file: &File, metadata: &Metadata) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let current = file.metadata()?;
+    if current.uid() == metadata.uid() && current.gid() == metadata.gid() {
+        return Ok(());
+    }
+    std::os::unix::fs::fchown(file, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_: &File, _: &Metadata) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use crate::buffer::Buffer;
+use crate::operator::Range;
+use crate::word::{self, Class};
+
+/// Matches counted at most for [`count`], like vim's `maxcount`.
+const MAX_COUNT: usize = 99;
+
+/// How long [`count`] may take before it gives up on a large buffer.
+const COUNT_TIMEOUT: Duration = Duration::from_millis(40);
+
+/// The last pattern searched for with `/`, `?`, `*` or `#`, and which way
+/// `n` repeats it.
+#[derive(Debug, Clone)]
+pub struct Search {
+    pub pattern: String,
+    pub forward: bool,
+}
+
+/// Compiles a search pattern, which uses Rust's regular expression syntax
+/// with `\<` and `\>` for word boundaries. Case is ignored with
+/// `ignorecase`, unless `smartcase` is set too and the pattern has a
+/// capital letter in it.
+pub fn compile(pattern: &str, ignorecase: bool, smartcase: bool) -> Result<Regex, regex::Error> {
+    let ignore = ignorecase && !(smartcase && has_capital(pattern));
+    RegexBuilder::new(pattern).case_insensitive(ignore).build()
+}
+
+/// Whether `pattern` has a capital letter outside of escapes like `\S`.
+fn has_capital(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+/// The matches in a line, as char columns from start to end. Matches don't
+/// span lines.
+pub fn matches(buffer: &Buffer, regex: &Regex, y: usize) -> Vec<(usize, usize)> {
+    let Some(line) = buffer.get(y) else { return Vec::new() };
+    let line = line.slice(..buffer.line_len(y));
+    let text: Cow<str> = line.into();
+    regex
+        .find_iter(&text)
+        .map(|m| (line.byte_to_char(m.start()), line.byte_to_char(m.end())))
+        .collect()
+}
+
+/// The first match after `from`, or before it going back, searching from
+/// the other end of the buffer if there is none and `wrapscan` is set.
+/// Also returns whether the search wrapped around.
+pub fn find(buffer: &Buffer, regex: &Regex, from: (usize, usize), forward: bool, wrapscan: bool) -> Option<(Range, bool)> {
+    let (x, y) = from;
+    let last = buffer.len() - 1;
+    let found = |y: usize, (start, end): (usize, usize)| Range { start: (start, y), end: (end, y), linewise: false };
+    // The first match on a line, or the first after a column.
+    let first = |y: usize, after: Option<usize>| matches(buffer, regex, y).into_iter().find(|m| after.is_none_or(|x| m.0 > x));
+    let last_before = |y: usize, before: Option<usize>| {
+        matches(buffer, regex, y).into_iter().rev().find(|m| before.is_none_or(|x| m.0 < x))
+    };
+
+    if forward {
+        if let Some(m) = first(y, Some(x)) {
+            return Some((found(y, m), false));
+        }
+        if let Some((y, m)) = (y + 1..=last).find_map(|y| Some((y, first(y, None)?))) {
+            return Some((found(y, m), false));
+        }
+        if !wrapscan {
+            return None;
+        }
+        // Around to the top and back to the cursor.
+        let (y, m) = (0..=y).find_map(|y| Some((y, first(y, None)?)))?;
+        return (y < from.1 || m.0 <= x).then(|| (found(y, m), true));
+    }
+
+    if let Some(m) = last_before(y, Some(x)) {
+        return Some((found(y, m), false));
+    }
+    if let Some((y, m)) = (0..y).rev().find_map(|y| Some((y, last_before(y, None)?))) {
+        return Some((found(y, m), false));
+    }
+    if !wrapscan {
+        return None;
+    }
+    let (y, m) = (y..=last).rev().find_map(|y| Some((y, last_before(y, None)?)))?;
+    (y > from.1 || m.0 >= x).then(|| (found(y, m), true))
+}
+
+/// The start of the text `*` and `#` search for, and a pattern for it: the
+/// keyword under or after the cursor as a whole word, or if there is none
+/// the non-blank text there.
+pub fn word_pattern(buffer: &Buffer, (x, y): (usize, usize)) -> Option<(usize, String)> {
+    let line: Vec<char> = buffer.get(y)?.chars().take(buffer.line_len(y)).collect();
+    let is_keyword = |i: usize| word::class(line[i], false) == Class::Keyword;
+    let is_text = |i: usize| word::class(line[i], false) != Class::Blank;
+    let (mut start, keyword) = match (x..line.len()).find(|&i| is_keyword(i)) {
+        Some(i) => (i, true),
+        None => ((x..line.len()).find(|&i| is_text(i))?, false),
+    };
+    let part = |i: usize| if keyword { is_keyword(i) } else { is_text(i) };
+    while start > 0 && part(start - 1) {
+        start -= 1;
+    }
+    let end = (start..line.len()).find(|&i| !part(i)).unwrap_or(line.len());
+    let text: String = line[start..end].iter().collect();
+    let pattern = regex::escape(&text);
+    Some((start, if keyword { format!(r"\<{pattern}\>") } else { pattern }))
+}
+
+/// Which match starts at `pos` and how many there are, as `[3/17]`. The
+/// count stops after [`MAX_COUNT`] matches, as in `[3/>99]`, and shows as
+/// `[?/??]` if it runs out of time.
+pub fn count(buffer: &Buffer, regex: &Regex, (x, y): (usize, usize)) -> String {
+    let started = Instant::now();
+    let (mut total, mut current) = (0, None);
+    for line in 0..buffer.len() {
+        if started.elapsed() > COUNT_TIMEOUT {
+            return "[?/??]".to_string();
+        }
+        for (start, _) in matches(buffer, regex, line) {
+            total += 1;
+            if (line, start) == (y, x) {
+                current = Some(total);
+            }
+            if total > MAX_COUNT {
+                let current = current.map_or(format!(">{MAX_COUNT}"), |n| n.to_string());
+                return format!("[{current}/>{MAX_COUNT}]");
+            }
+        }
+    }
+    format!("[{}/{total}]", current.map_or("?".to_string(), |n| n.to_string()))
+}
@@ -0,0 +1,148 @@
+use regex::Regex;
+
+use crate::ex;
+
+/// A parsed `:sort` argument, as in `:sort nu /\d+/`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sort {
+    /// `n`: by the first number in each line rather than its text. Lines
+    /// without one come first.
+    pub numeric: bool,
+    /// `i`: ignoring case.
+    pub ignore_case: bool,
+    /// `u`: keeps only the first of lines that compare equal.
+    pub unique: bool,
+    /// `r`: by the text the pattern matches rather than what follows it.
+    pub use_match: bool,
+    /// Empty to use the last search pattern.
+    pub pattern: Option<String>,
+}
+
+/// Parses what follows `:sort`: flags, and a pattern between delimiters
+/// anywhere among them.
+pub fn parse(arg: &str) -> Result<Sort, String> {
+    let mut sort = Sort::default();
+    let mut rest = arg;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            ' ' => {}
+            'n' => sort.numeric = true,
+            'i' => sort.ignore_case = true,
+            'u' => sort.unique = true,
+            'r' => sort.use_match = true,
+            c if c.is_alphanumeric() || matches!(c, '\\' | '"') || sort.pattern.is_some() => {
+                return Err(format!("E474: Invalid argument: {rest}"));
+            }
+            _ => {
+                let (pattern, after) = ex::split_pattern(rest)?;
+                sort.pattern = Some(pattern);
+                rest = after;
+                continue;
+            }
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(sort)
+}
+
+/// What lines are compared by: their text, or a number in it.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Number(Option<i128>),
+    Text(String),
+}
+
+/// Sorts `lines` in place, keeping lines that compare equal in the order
+/// they were in. With `regex`, lines are compared by what follows its
+/// first match, or by the match itself with `r`, and those it doesn't
+/// match come first. With `reverse` the result is turned around. With `u`
+/// only the first of lines that compare equal is kept.
+pub fn sort(lines: &mut Vec<String>, sort: &Sort, regex: Option<&Regex>, reverse: bool) {
+    let key = |line: &str| {
+        let text = match regex.map(|regex| regex.find(line)) {
+            None => line,
+            Some(Some(m)) if sort.use_match => m.as_str(),
+            Some(Some(m)) => &line[m.end()..],
+            Some(None) => "",
+        };
+        if sort.numeric {
+            Key::Number(number(text))
+        } else if sort.ignore_case {
+            Key::Text(text.to_lowercase())
+        } else {
+            Key::Text(text.to_string())
+        }
+    };
+    let mut keyed: Vec<(Key, String)> = lines.drain(..).map(|line| (key(&line), line)).collect();
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    if reverse {
+        keyed.reverse();
+    }
+    if sort.unique {
+        keyed.dedup_by(|a, b| a.0 == b.0);
+    }
+    lines.extend(keyed.into_iter().map(|(_, line)| line));
+}
+
+/// The first decimal number in `text`, with a `-` right before it.
+fn number(text: &str) -> Option<i128> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let len = text[start..].find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len() - start);
+    // Numbers too big to parse sort after all others.
+    let n = text[start..start + len].parse().unwrap_or(i128::MAX);
+    Some(if text[..start].ends_with('-') { -n } else { n })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(lines: &[&str], arg: &str, reverse: bool) -> Vec<String> {
+        let sort = parse(arg).unwrap();
+        let regex = sort.pattern.as_deref().map(|pattern| Regex::new(pattern).unwrap());
+        let mut lines = lines.iter().map(|line| line.to_string()).collect();
+        super::sort(&mut lines, &sort, regex.as_ref(), reverse);
+        lines
+    }
+
+    #[test]
+    fn parses_flags_and_pattern() {
+        let sort = parse("n /\\d+/ ru").unwrap();
+        assert_eq!(sort, Sort { numeric: true, unique: true, use_match: true, pattern: Some("\\d+".to_string()), ..Sort::default() });
+        assert_eq!(parse("x").unwrap_err(), "E474: Invalid argument: x");
+        assert_eq!(parse("/a/ /b/").unwrap_err(), "E474: Invalid argument: /b/");
+    }
+
+    #[test]
+    fn by_text() {
+        assert_eq!(sorted(&["b", "B", "a", "A"], "", false), ["A", "B", "a", "b"]);
+        assert_eq!(sorted(&["b", "B", "a", "A"], "", true), ["b", "a", "B", "A"]);
+        assert_eq!(sorted(&["b", "B", "a", "A"], "i", false), ["a", "A", "b", "B"]);
+    }
+
+    #[test]
+    fn by_number() {
+        assert_eq!(sorted(&["x10", "x9", "y", "a-3"], "n", false), ["y", "a-3", "x9", "x10"]);
+        assert_eq!(sorted(&["x10", "x9", "y", "a-3"], "n", true), ["x10", "x9", "a-3", "y"]);
+    }
+
+    #[test]
+    fn unique() {
+        assert_eq!(sorted(&["b", "a", "b", "B"], "u", false), ["B", "a", "b"]);
+        assert_eq!(sorted(&["b", "a", "b", "B"], "iu", false), ["a", "b"]);
+        // Lines with the same number are the same, whatever else is on them.
+        assert_eq!(sorted(&["x10", "x9", "y", "x9", "X9", "a-3"], "nu", false), ["y", "a-3", "x9", "x10"]);
+    }
+
+    #[test]
+    fn by_pattern() {
+        let lines = ["x=3 b", "y=1 c", "none", "z=2 a"];
+        // What follows the match, with lines that don't match first.
+        assert_eq!(sorted(&lines, "/=\\d /", false), ["none", "z=2 a", "x=3 b", "y=1 c"]);
+        assert_eq!(sorted(&lines, "r /\\d/", false), ["none", "y=1 c", "z=2 a", "x=3 b"]);
+        assert_eq!(sorted(&lines, "r /\\d/", true), ["x=3 b", "z=2 a", "y=1 c", "none"]);
+        assert_eq!(sorted(&lines, "n /=/", false), ["none", "y=1 c", "z=2 a", "x=3 b"]);
+        // Only the key counts for `u`.
+        assert_eq!(sorted(&["a=1", "b=1", "c=2"], "u /=/", false), ["a=1", "c=2"]);
+    }
+}
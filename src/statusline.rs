@@ -0,0 +1,113 @@
+use std::path::Path;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::buffer::Buffer;
+use crate::options::{self, Options};
+
+/// What the status line describes.
+pub struct Status<'a> {
+    pub buffer: &'a mut Buffer,
+    pub options: &'a mut Options,
+    /// Cursor line and column, zero-based. `col` counts chars, `vcol`
+    /// screen columns.
+    pub line: usize,
+    pub col: usize,
+    pub vcol: usize,
+    /// First and last buffer lines on screen.
+    pub top: usize,
+    pub bottom: usize,
+}
+
+/// Expands a `statusline` format string, returning the text before and
+/// after `%=`. Supports these items from vim:
+///
+/// - `%f` file name as given, `%F` full path, `%t` name without directory
+/// - `%m` `[+]` when modified, `%r` `[RO]` when readonly, `%y` file type
+/// - `%l` line, `%L` number of lines, `%c` column, `%v` screen column
+/// - `%p` percentage through the file, `%P` `Top`, `Bot`, `All` or `NN%`
+/// - `%{&name}` the value of an option, `%%` a percent sign
+pub fn render(format: &str, status: &mut Status) -> (String, String) {
+    let (mut left, mut right) = (String::new(), String::new());
+    let mut in_right = false;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        let out = if in_right { &mut right } else { &mut left };
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('=') => in_right = true,
+            Some('{') => {
+                let expr: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                if let Some(name) = expr.strip_prefix('&') {
+                    let settings = &mut (&mut *status.options, &mut *status.buffer);
+                    out.push_str(&options::get(settings, name).unwrap_or_default());
+                }
+            }
+            Some(item) => out.push_str(&status.item(item)),
+            None => out.push('%'),
+        }
+    }
+    (left, right)
+}
+
+impl Status<'_> {
+    fn item(&self, item: char) -> String {
+        let buffer = &*self.buffer;
+        let file = buffer.file.as_deref();
+        match item {
+            'f' => file.unwrap_or("[No Name]").to_string(),
+            'F' => file
+                .and_then(|f| std::path::absolute(f).ok())
+                .map_or("[No Name]".to_string(), |p| p.display().to_string()),
+            't' => file.map_or("[No Name]".to_string(), |f| {
+                Path::new(f).file_name().map_or(f.to_string(), |n| n.to_string_lossy().into_owned())
+            }),
+            'm' if buffer.modified() => "[+]".to_string(),
+            'r' if buffer.readonly => "[RO]".to_string(),
+            'y' if !buffer.filetype.is_empty() => format!("[{}]", buffer.filetype),
+            'l' => (self.line + 1).to_string(),
+            'L' => buffer.len().to_string(),
+            'c' => (self.col + 1).to_string(),
+            'v' => (self.vcol + 1).to_string(),
+            'p' => ((self.line + 1) * 100 / buffer.len()).to_string(),
+            'P' => {
+                let above = self.top;
+                let below = (buffer.len() - 1).saturating_sub(self.bottom);
+                match (above, below) {
+                    (0, 0) => "All".to_string(),
+                    (0, _) => "Top".to_string(),
+                    (_, 0) => "Bot".to_string(),
+                    _ => format!("{}%", above * 100 / (above + below)),
+                }
+            }
+            '%' => "%".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Pads `text` to `width` columns, or cuts it down from the start with a
+/// `<` in front when it is too long, the way vim shortens its status line.
+pub fn fit(text: &str, width: usize) -> String {
+    let text_width = text.width();
+    if text_width <= width {
+        return format!("{text}{:pad$}", "", pad = width - text_width);
+    }
+    if width == 0 {
+        return String::new();
+    }
+
+    let mut kept = 1;
+    let mut start = text.len();
+    for (i, c) in text.char_indices().rev() {
+        let w = c.width().unwrap_or(0);
+        if kept + w > width {
+            break;
+        }
+        kept += w;
+        start = i;
+    }
+    format!("<{}{:pad$}", &text[start..], "", pad = width - kept)
+}
@@ -0,0 +1,127 @@
+use regex::Captures;
+
+/// A parsed `:s/pattern/replacement/flags`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Substitute {
+    /// Empty to use the last search pattern.
+    pub pattern: String,
+    pub replacement: String,
+    /// `g`: every match on a line rather than only the first.
+    pub global: bool,
+    /// `c`: ask before each replacement.
+    pub confirm: bool,
+    /// `i` or `I`, overriding `ignorecase` and `smartcase`.
+    pub ignore_case: Option<bool>,
+    /// `&`: the flags of the previous substitute apply as well.
+    pub keep_flags: bool,
+}
+
+/// Parses what follows `:s`. The first character is the delimiter, which
+/// can be used in the pattern and replacement escaped with a backslash.
+/// The replacement and flags can be left out, as in `:s/foo`.
+pub fn parse(arg: &str) -> Result<Substitute, String> {
+    let mut chars = arg.chars();
+    let delimiter = match chars.next() {
+        Some(c) if !c.is_alphanumeric() && !matches!(c, '\\' | '"' | '|' | ' ') => c,
+        _ => return Err(format!("E146: Regular expressions can't be delimited by letters: {arg}")),
+    };
+
+    let mut parts = vec![String::new()];
+    let mut rest = String::new();
+    while let Some(c) = chars.next() {
+        if parts.len() == 3 {
+            rest.push(c);
+            continue;
+        }
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => part.extend(['\\', next]),
+                None => part.push('\\'),
+            },
+            c if c == delimiter => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+
+    let mut sub = Substitute {
+        pattern: parts[0].clone(),
+        replacement: parts.get(1).cloned().unwrap_or_default(),
+        ..Substitute::default()
+    };
+    for (i, flag) in rest.char_indices() {
+        match flag {
+            'g' => sub.global = true,
+            'c' => sub.confirm = true,
+            'i' => sub.ignore_case = Some(true),
+            'I' => sub.ignore_case = Some(false),
+            '&' if i == 0 => sub.keep_flags = true,
+            _ => return Err(format!("E488: Trailing characters: {}", &rest[i..])),
+        }
+    }
+    Ok(sub)
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+/// The text a match is replaced with. `&` and `\0` stand for the whole
+/// match and `\1` to `\9` for its groups, `\r` and `\n` for a line break
+/// and `\t` for a tab. `\u` and `\l` change the case of the next character,
+/// and `\U` and `\L` of everything up to `\E` or `\e`.
+pub fn expand(replacement: &str, caps: &Captures) -> String {
+    let mut text = String::new();
+    let (mut next, mut all) = (None, None);
+    let mut push = |s: &str, next: &mut Option<Case>, all: Option<Case>| {
+        for c in s.chars() {
+            match next.take().or(all) {
+                Some(Case::Upper) => text.extend(c.to_uppercase()),
+                Some(Case::Lower) => text.extend(c.to_lowercase()),
+                None => text.push(c),
+            }
+        }
+    };
+
+    let group = |n: usize| caps.get(n).map_or("", |m| m.as_str());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => push(group(0), &mut next, all),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => push(group(d as usize - '0' as usize), &mut next, all),
+                Some('r' | 'n') => push("\n", &mut None, None),
+                Some('t') => push("\t", &mut next, all),
+                Some('u') => next = Some(Case::Upper),
+                Some('l') => next = Some(Case::Lower),
+                Some('U') => all = Some(Case::Upper),
+                Some('L') => all = Some(Case::Lower),
+                Some('E' | 'e') => all = None,
+                Some(c) => push(c.encode_utf8(&mut [0; 4]), &mut next, all),
+                None => push("\\", &mut next, all),
+            },
+            c => push(c.encode_utf8(&mut [0; 4]), &mut next, all),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags() {
+        let sub = parse("#a\\#b#c#&gI").unwrap();
+        assert_eq!(sub.pattern, "a#b");
+        assert_eq!(sub.replacement, "c");
+        assert!(sub.keep_flags && sub.global && !sub.confirm);
+        assert_eq!(sub.ignore_case, Some(false));
+        assert_eq!(parse("/a/b/g&").unwrap_err(), "E488: Trailing characters: &");
+        assert_eq!(parse("/a/b/x").unwrap_err(), "E488: Trailing characters: x");
+        assert!(parse("xaxbx").is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use ropey::Rope;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, TryLockError};
+use std::time::SystemTime;
+
+use crate::history;
+
+const SWAP_MAGIC: &str = "OXSWAP 1";
+
+/// Largest diff, in old lines times new lines, that gets a line-by-line
+/// comparison. Bigger ones are shown as one block of removed and added
+/// lines.
+const MAX_DIFF_CELLS: usize = 1 << 20;
+
+/// Where unsaved changes to `file` are kept, see [`history::state_file`].
+pub fn swap_file(file: &str) -> Option<PathBuf> {
+    let mut path = history::state_file("swap", file)?;
+    path.as_mut_os_string().push(".swp");
+    Some(path)
+}
+
+/// The unsaved text of a buffer, as it would be recovered. Ropes share
+/// their contents when cloned, so taking one after every change is cheap.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub file: String,
+    pub text: Rope,
+}
+
+impl Snapshot {
+    /// Replaces the swap file at `path` with this snapshot, see
+    /// [`history::write_state_file`].
+    pub fn write(&self) -> io::Result<()> {
+        history::write_state_file(&self.path, |out| {
+            write!(out, "{SWAP_MAGIC}\npid {}\nfile {}\n\n", std::process::id(), self.file)?;
+            for chunk in self.text.chunks() {
+                out.write_all(chunk.as_bytes())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A swap file left behind by an earlier session, or by another editor
+/// that has the same file open.
+pub struct Found {
+    pub path: PathBuf,
+    pub pid: u32,
+    pub file: String,
+    pub modified: Option<SystemTime>,
+    pub text: String,
+}
+
+impl Found {
+    /// Whether the editor that wrote the swap file is still running.
+    pub fn running(&self) -> bool {
+        self.pid != std::process::id() && process_running(self.pid)
+    }
+}
+
+/// Reads the swap file at `path`, if there is one.
+pub fn read(path: &Path) -> io::Result<Option<Found>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a swap file");
+
+    let data = String::from_utf8(data).map_err(|_| invalid())?;
+    let (header, text) = data.split_once("\n\n").ok_or_else(invalid)?;
+    let mut lines = header.lines();
+    if lines.next() != Some(SWAP_MAGIC) {
+        return Err(invalid());
+    }
+
+    let (mut pid, mut file) = (0, String::new());
+    for line in lines {
+        match line.split_once(' ') {
+            Some(("pid", value)) => pid = value.parse().map_err(|_| invalid())?,
+            Some(("file", value)) => file = value.to_string(),
+            _ => {}
+        }
+    }
+
+    Ok(Some(Found {
+        path: path.to_path_buf(),
+        pid,
+        file,
+        modified: fs::metadata(path).and_then(|m| m.modified()).ok(),
+        text: text.to_string(),
+    }))
+}
+
+static LATEST: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+/// Keeps `snapshot` around for [`flush`]. `None` means there is nothing
+/// that would need recovering.
+pub fn remember(snapshot: Option<Snapshot>) {
+    *LATEST.lock().unwrap_or_else(|e| e.into_inner()) = snapshot;
+}
+
+/// Writes the last remembered snapshot to its swap file. Meant for the
+/// panic hook, so it gives up rather than wait for the lock. Returns the
+/// swap file that was written.
+pub fn flush() -> Option<PathBuf> {
+    let latest = match LATEST.try_lock() {
+        Ok(latest) => latest,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return None,
+    };
+    let snapshot = latest.as_ref()?;
+    snapshot.write().ok()?;
+    Some(snapshot.path.clone())
+}
+
+/// Compares two texts line by line. Unchanged lines are left out; each run
+/// of changes starts with a `@@ line N @@` header giving its line in `old`,
+/// followed by `-` lines only in `old` and `+` lines only in `new`.
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.split('\n').collect();
+    let new: Vec<&str> = new.split('\n').collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut out = Vec::new();
+    let (mut i, mut j, mut line) = (0, 0, prefix + 1);
+    let mut in_hunk = false;
+    fn change(out: &mut Vec<String>, in_hunk: &mut bool, line: usize, text: String) {
+        if !*in_hunk {
+            out.push(format!("@@ line {line} @@"));
+            *in_hunk = true;
+        }
+        out.push(text);
+    }
+
+    if a.len() * b.len() > MAX_DIFF_CELLS {
+        for text in a {
+            change(&mut out, &mut in_hunk, line, format!("-{text}"));
+        }
+        for text in b {
+            change(&mut out, &mut in_hunk, line, format!("+{text}"));
+        }
+        return out;
+    }
+
+    // Longest common subsequence of the changed region, from the end.
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i * width + j] = if a[i] == b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            in_hunk = false;
+            (i, j, line) = (i + 1, j + 1, line + 1);
+        } else if j == b.len() || (i < a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1]) {
+            change(&mut out, &mut in_hunk, line, format!("-{}", a[i]));
+            (i, line) = (i + 1, line + 1);
+        } else {
+            change(&mut out, &mut in_hunk, line, format!("+{}", b[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else { return false };
+    if pid <= 0 {
+        return false;
+    }
+    // Signal 0 only checks that the process exists; EPERM means it does
+    // but belongs to someone else.
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_running(_: u32) -> bool {
+    false
+}
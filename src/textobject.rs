@@ -0,0 +1,503 @@
+use ropey::RopeSlice;
+
+use crate::buffer::Buffer;
+use crate::operator::Range;
+use crate::word::{self, Class};
+
+/// Text around the cursor that an operator or Visual mode can select, as
+/// `w` in `diw` or `(` in `ca(`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextObject {
+    Word,
+    BigWord,
+    Sentence,
+    Paragraph,
+    /// A string quoted with the given character.
+    Quote(char),
+    /// A pair of brackets, opening and closing.
+    Bracket(char, char),
+    /// An XML or HTML element.
+    Tag,
+}
+
+impl TextObject {
+    /// The object typed as `key` after `i` or `a`.
+    pub fn from_key(key: char) -> Option<Self> {
+        Some(match key {
+            'w' => TextObject::Word,
+            'W' => TextObject::BigWord,
+            's' => TextObject::Sentence,
+            'p' => TextObject::Paragraph,
+            '"' | '\'' | '`' => TextObject::Quote(key),
+            '(' | ')' | 'b' => TextObject::Bracket('(', ')'),
+            '[' | ']' => TextObject::Bracket('[', ']'),
+            '{' | '}' | 'B' => TextObject::Bracket('{', '}'),
+            '<' | '>' => TextObject::Bracket('<', '>'),
+            't' => TextObject::Tag,
+            _ => return None,
+        })
+    }
+}
+
+/// The text `object` covers at `cursor`: only its contents if `inner`,
+/// otherwise with the white space or delimiters around it. `count` selects
+/// several words, sentences or paragraphs, or an enclosing pair of
+/// brackets or tags further out.
+pub fn select(buffer: &Buffer, object: TextObject, inner: bool, cursor: (usize, usize), count: usize) -> Option<Range> {
+    let count = count.max(1);
+    match object {
+        TextObject::Word => words(buffer, inner, false, cursor, count),
+        TextObject::BigWord => words(buffer, inner, true, cursor, count),
+        TextObject::Sentence => sentences(buffer, inner, cursor, count),
+        TextObject::Paragraph => paragraphs(buffer, inner, cursor.1, count),
+        TextObject::Quote(quote) => quoted(buffer, inner, quote, cursor),
+        TextObject::Bracket(open, close) => brackets(buffer, inner, open, close, cursor, count),
+        TextObject::Tag => tags(buffer, inner, cursor, count),
+    }
+}
+
+/// A charwise range between two char indices.
+fn span(buffer: &Buffer, start: usize, end: usize) -> Range {
+    Range { start: buffer.char_to_pos(start), end: buffer.char_to_pos(end), linewise: false }
+}
+
+/// `iw` counts runs of blanks as words too; `aw` takes each word with the
+/// blanks after it, or before it if there are none after.
+fn words(buffer: &Buffer, inner: bool, big: bool, (x, y): (usize, usize), count: usize) -> Option<Range> {
+    let line: Vec<char> = buffer.get(y)?.chars().collect();
+    if line.is_empty() {
+        return None;
+    }
+    let len = line.len();
+    let class = |i: usize| word::class(line[i], big);
+    let run_end = |i: usize| (i..len).find(|&j| class(j) != class(i)).unwrap_or(len);
+
+    let x = x.min(len - 1);
+    let on_blank = class(x) == Class::Blank;
+    let mut start = x;
+    while start > 0 && class(start - 1) == class(x) {
+        start -= 1;
+    }
+
+    let mut end = start;
+    for _ in 0..count {
+        if end == len {
+            break;
+        }
+        let first = class(end);
+        end = run_end(end);
+        if !inner && end < len && (first == Class::Blank || class(end) == Class::Blank) {
+            end = run_end(end);
+        }
+    }
+    if !inner && !on_blank && class(end - 1) != Class::Blank {
+        while start > 0 && class(start - 1) == Class::Blank {
+            start -= 1;
+        }
+    }
+    Some(Range { start: (start, y), end: (end, y), linewise: false })
+}
+
+/// Paragraphs are separated by lines with nothing but white space on them.
+/// `ip` counts a run of such lines as a paragraph too; `ap` takes each
+/// paragraph with the blank lines after it, or before it if there are none
+/// after.
+fn paragraphs(buffer: &Buffer, inner: bool, y: usize, count: usize) -> Option<Range> {
+    let blank = |y: usize| buffer.get(y).is_none_or(|line| line.chars().all(char::is_whitespace));
+    let last = buffer.len() - 1;
+    let run_end = |y: usize| (y..=last).find(|&z| blank(z) != blank(y)).map_or(last, |z| z - 1);
+
+    let mut start = y;
+    while start > 0 && blank(start - 1) == blank(y) {
+        start -= 1;
+    }
+
+    let mut end = start;
+    for i in 0..count {
+        if i > 0 {
+            if end == last {
+                return None;
+            }
+            end += 1;
+        }
+        let first = blank(end);
+        end = run_end(end);
+        if !inner && end < last && (first || blank(end + 1)) {
+            end = run_end(end + 1);
+        }
+    }
+    if !inner && !blank(y) && !blank(end) {
+        while start > 0 && blank(start - 1) {
+            start -= 1;
+        }
+    }
+    Some(Range { start: (0, start), end: (0, end), linewise: true })
+}
+
+/// A sentence ends at `.`, `!` or `?`, optionally followed by closing
+/// brackets and quotes, and then white space or the end of the paragraph.
+/// Like words, `is` counts the white space between sentences as a sentence
+/// of its own and `as` takes a sentence with the white space after it.
+fn sentences(buffer: &Buffer, inner: bool, (x, y): (usize, usize), count: usize) -> Option<Range> {
+    let blank = |y: usize| buffer.get(y).is_none_or(|line| line.chars().all(char::is_whitespace));
+    if blank(y) {
+        return paragraphs(buffer, true, y, 1);
+    }
+    let (mut first, mut last) = (y, y);
+    while first > 0 && !blank(first - 1) {
+        first -= 1;
+    }
+    while last + 1 < buffer.len() && !blank(last + 1) {
+        last += 1;
+    }
+    let offset = buffer.char_idx(0, first);
+    let end = buffer.char_idx(buffer.line_len(last), last);
+    let text: Vec<char> = buffer.text().slice(offset..end).chars().collect();
+
+    // Alternating sentences and the white space between them, as
+    // `(start, end, is_blank)`.
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let start = i;
+        let blank = text[i].is_whitespace();
+        if blank {
+            while i < text.len() && text[i].is_whitespace() {
+                i += 1;
+            }
+        } else {
+            while i < text.len() {
+                let c = text[i];
+                i += 1;
+                if matches!(c, '.' | '!' | '?') {
+                    while i < text.len() && matches!(text[i], ')' | ']' | '"' | '\'') {
+                        i += 1;
+                    }
+                    if i == text.len() || text[i].is_whitespace() {
+                        break;
+                    }
+                }
+            }
+        }
+        parts.push((start, i, blank));
+    }
+
+    let cursor = buffer.char_idx(x, y) - offset;
+    let k = parts.iter().position(|&(_, end, _)| cursor < end).unwrap_or(parts.len() - 1);
+    let mut start = parts[k].0;
+    let last_part = if inner { k + count - 1 } else { k + 2 * count - 1 }.min(parts.len() - 1);
+    let end = parts[last_part].1;
+    if !inner && !parts[k].2 && !parts[last_part].2 && k > 0 {
+        start = parts[k - 1].0;
+    }
+    Some(span(buffer, offset + start, offset + end))
+}
+
+/// Quotes pair up from the start of the line, skipping ones escaped with
+/// a backslash. `a"` takes the white space after the closing quote too, or
+/// before the opening one if there is none after.
+fn quoted(buffer: &Buffer, inner: bool, quote: char, (x, y): (usize, usize)) -> Option<Range> {
+    let line: Vec<char> = buffer.get(y)?.chars().collect();
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (i, &c) in line.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            quotes.push(i);
+        }
+    }
+
+    let (open, close) = match quotes.iter().position(|&q| q == x) {
+        Some(i) if i % 2 == 0 => (x, *quotes.get(i + 1)?),
+        Some(i) => (quotes[i - 1], x),
+        None => match (quotes.iter().rev().find(|&&q| q < x), quotes.iter().find(|&&q| q > x)) {
+            (Some(&open), Some(&close)) => (open, close),
+            // The cursor is before the first string on the line.
+            (None, Some(_)) => {
+                let i = quotes.iter().position(|&q| q > x)?;
+                (quotes[i], *quotes.get(i + 1)?)
+            }
+            _ => return None,
+        },
+    };
+
+    if inner {
+        return Some(Range { start: (open + 1, y), end: (close, y), linewise: false });
+    }
+    let (mut start, mut end) = (open, close + 1);
+    let is_blank = |c: &char| c.is_whitespace();
+    let after = line[end..].iter().take_while(|c| is_blank(c)).count();
+    if after > 0 {
+        end += after;
+    } else {
+        start -= line[..start].iter().rev().take_while(|c| is_blank(c)).count();
+    }
+    Some(Range { start: (start, y), end: (end, y), linewise: false })
+}
+
+/// The `count`th pair of brackets around the cursor, counting a bracket
+/// under the cursor. When the brackets are on lines of their own, as in a
+/// block, `i{` takes just the lines between them.
+fn brackets(buffer: &Buffer, inner: bool, open: char, close: char, (x, y): (usize, usize), count: usize) -> Option<Range> {
+    let text = buffer.text();
+    let cursor = buffer.char_idx(x, y);
+    let mut i = if text.get_char(cursor) == Some(open) { cursor + 1 } else { cursor };
+    let (mut depth, mut remaining) = (0, count);
+    let start = loop {
+        if i == 0 {
+            return None;
+        }
+        i -= 1;
+        match text.char(i) {
+            c if c == close => depth += 1,
+            c if c == open && depth > 0 => depth -= 1,
+            c if c == open => {
+                remaining -= 1;
+                if remaining == 0 {
+                    break i;
+                }
+            }
+            _ => {}
+        }
+    };
+
+    let mut depth = 0;
+    let end = (start + 1..text.len_chars()).find(|&i| match text.char(i) {
+        c if c == open => {
+            depth += 1;
+            false
+        }
+        c if c == close && depth > 0 => {
+            depth -= 1;
+            false
+        }
+        c => c == close,
+    })?;
+
+    if !inner {
+        return Some(span(buffer, start, end + 1));
+    }
+    let (open_x, open_y) = buffer.char_to_pos(start);
+    let (close_x, close_y) = buffer.char_to_pos(end);
+    let ends_line = open_x + 1 == buffer.line_len(open_y);
+    let starts_line = buffer.get(close_y)?.chars().take(close_x).all(char::is_whitespace);
+    if ends_line && starts_line && close_y > open_y + 1 {
+        return Some(Range { start: (0, open_y + 1), end: (0, close_y - 1), linewise: true });
+    }
+    Some(span(buffer, start + 1, end))
+}
+
+/// The `count`th XML or HTML element around the cursor. `it` is what is
+/// between its tags, `at` includes them. Tags are found by walking out
+/// from the cursor, as brackets are, rather than parsing the whole text.
+fn tags(buffer: &Buffer, inner: bool, (x, y): (usize, usize), count: usize) -> Option<Range> {
+    let text = buffer.text();
+    let cursor = buffer.char_idx(x, y);
+    // A tag under the cursor belongs to the element it opens or closes.
+    let from = match tag_under(text, cursor) {
+        Some(tag) if tag.closing => tag.start,
+        Some(tag) => tag.end,
+        None => cursor,
+    };
+
+    // Going back, closing tags of elements before the cursor wait for
+    // their opening tags; any other opening tag starts an element around
+    // the cursor if a closing tag for it follows.
+    let (mut pos, mut after, mut remaining) = (from, from, count);
+    let mut closed: Vec<String> = Vec::new();
+    while let Some(tag) = tag_before(text, pos) {
+        pos = tag.start;
+        if tag.closing {
+            closed.push(tag.name);
+            continue;
+        }
+        if let Some(k) = closed.iter().rposition(|name| *name == tag.name) {
+            closed.truncate(k);
+            continue;
+        }
+        // An element without a closing tag, like `<br>`, is skipped.
+        let Some(close) = closing_tag(text, after, &tag.name) else { continue };
+        after = close.end;
+        remaining -= 1;
+        if remaining == 0 {
+            return Some(if inner { span(buffer, tag.end, close.start) } else { span(buffer, tag.start, close.end) });
+        }
+    }
+    None
+}
+
+/// An opening or closing tag, from its `<` up to after its `>`.
+struct Tag {
+    name: String,
+    closing: bool,
+    start: usize,
+    end: usize,
+}
+
+/// The tag in `text[lt..=gt]`, unless it is one that doesn't open or close
+/// an element, like `<br/>`, `<!-- -->` or `<?xml ?>`.
+fn parse_tag(text: RopeSlice, lt: usize, gt: usize) -> Option<Tag> {
+    let tag = text.slice(lt + 1..gt).to_string();
+    let (name, closing) = match tag.strip_prefix('/') {
+        Some(name) => (name.trim().to_string(), true),
+        None if tag.ends_with('/') || tag.starts_with(['!', '?']) => return None,
+        None => (tag.chars().take_while(|c| !c.is_whitespace()).collect(), false),
+    };
+    (!name.is_empty()).then_some(Tag { name, closing, start: lt, end: gt + 1 })
+}
+
+/// The last tag that ends at or before char `pos`.
+fn tag_before(text: RopeSlice, mut pos: usize) -> Option<Tag> {
+    let mut chars = text.chars_at(pos);
+    let mut find = |target: char| loop {
+        let c = chars.prev()?;
+        pos -= 1;
+        if c == target {
+            return Some(pos);
+        }
+    };
+    loop {
+        let gt = find('>')?;
+        let lt = find('<')?;
+        if let Some(tag) = parse_tag(text, lt, gt) {
+            return Some(tag);
+        }
+    }
+}
+
+/// The first tag that starts at or after char `pos`.
+fn tag_after(text: RopeSlice, mut pos: usize) -> Option<Tag> {
+    let mut chars = text.chars_at(pos);
+    let mut find = |target: char| loop {
+        pos += 1;
+        if chars.next()? == target {
+            return Some(pos - 1);
+        }
+    };
+    loop {
+        let lt = find('<')?;
+        let gt = find('>')?;
+        if let Some(tag) = parse_tag(text, lt, gt) {
+            return Some(tag);
+        }
+    }
+}
+
+/// The tag the char at `pos` is part of, if any.
+fn tag_under(text: RopeSlice, pos: usize) -> Option<Tag> {
+    if pos >= text.len_chars() {
+        return None;
+    }
+    let mut chars = text.chars_at(pos + 1);
+    let mut lt = pos + 1;
+    loop {
+        let c = chars.prev()?;
+        lt -= 1;
+        match c {
+            '<' => break,
+            '>' if lt < pos => return None,
+            _ => {}
+        }
+    }
+    tag_after(text, lt).filter(|tag| tag.start == lt && pos < tag.end)
+}
+
+/// The tag that closes an element named `name` opened before `pos`,
+/// skipping whole elements in between. Returns `None` if an element
+/// around it closes first.
+fn closing_tag(text: RopeSlice, mut pos: usize, name: &str) -> Option<Tag> {
+    let mut opened: Vec<String> = Vec::new();
+    while let Some(tag) = tag_after(text, pos) {
+        pos = tag.end;
+        if !tag.closing {
+            opened.push(tag.name);
+        } else if let Some(k) = opened.iter().rposition(|open| *open == tag.name) {
+            opened.truncate(k);
+        } else if tag.name == name {
+            return Some(tag);
+        } else {
+            return None;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text `i` or `a` followed by `key` selects in `text` with the
+    /// cursor at `cursor`.
+    fn selected(text: &str, cursor: (usize, usize), inner: bool, key: char, count: usize) -> Option<String> {
+        let buffer = Buffer::from_text(text);
+        let range = select(&buffer, TextObject::from_key(key)?, inner, cursor, count)?;
+        Some(buffer.text_in(&range))
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(selected("foo bar.baz", (5, 0), true, 'w', 1).as_deref(), Some("bar"));
+        assert_eq!(selected("foo bar baz", (1, 0), false, 'w', 1).as_deref(), Some("foo "));
+        assert_eq!(selected("foo bar baz", (1, 0), true, 'w', 3).as_deref(), Some("foo bar"));
+        assert_eq!(selected("foo bar.baz", (5, 0), true, 'W', 1).as_deref(), Some("bar.baz"));
+        // Without blanks after the word, those before it go with it.
+        assert_eq!(selected("foo bar", (5, 0), false, 'w', 1).as_deref(), Some(" bar"));
+        assert_eq!(selected("foo bar.baz", (5, 0), false, 'w', 1).as_deref(), Some(" bar"));
+    }
+
+    #[test]
+    fn quotes() {
+        let text = r#"say "a \"b\" c" and "d""#;
+        assert_eq!(selected(text, (6, 0), true, '"', 1).as_deref(), Some(r#"a \"b\" c"#));
+        assert_eq!(selected(text, (6, 0), false, '"', 1).as_deref(), Some(r#""a \"b\" c" "#));
+        // Before the first string on the line, it is the one selected.
+        assert_eq!(selected(text, (0, 0), true, '"', 1).as_deref(), Some(r#"a \"b\" c"#));
+        assert_eq!(selected("'x'", (0, 0), true, '"', 1), None);
+    }
+
+    #[test]
+    fn brackets() {
+        let text = "f(a, (b), c)";
+        assert_eq!(selected(text, (6, 0), true, '(', 1).as_deref(), Some("b"));
+        assert_eq!(selected(text, (6, 0), false, 'b', 1).as_deref(), Some("(b)"));
+        assert_eq!(selected(text, (6, 0), true, ')', 2).as_deref(), Some("a, (b), c"));
+        assert_eq!(selected(text, (1, 0), true, '(', 1).as_deref(), Some("a, (b), c"));
+        assert_eq!(selected(text, (0, 0), true, '(', 1), None);
+        // A block on lines of its own is taken as whole lines.
+        assert_eq!(selected("if x {\n    y;\n}", (4, 1), true, '{', 1).as_deref(), Some("    y;\n"));
+    }
+
+    #[test]
+    fn tags() {
+        let text = "<div><p>one <b>two</b></p><br>x</div>";
+        assert_eq!(selected(text, (16, 0), true, 't', 1).as_deref(), Some("two"));
+        assert_eq!(selected(text, (16, 0), false, 't', 1).as_deref(), Some("<b>two</b>"));
+        assert_eq!(selected(text, (16, 0), true, 't', 2).as_deref(), Some("one <b>two</b>"));
+        assert_eq!(selected(text, (8, 0), false, 't', 1).as_deref(), Some("<p>one <b>two</b></p>"));
+        // On a tag, the element it opens or closes.
+        assert_eq!(selected(text, (13, 0), true, 't', 1).as_deref(), Some("two"));
+        assert_eq!(selected(text, (20, 0), true, 't', 1).as_deref(), Some("two"));
+        // Elements without closing tags are skipped.
+        assert_eq!(selected(text, (30, 0), true, 't', 1).as_deref(), Some("<p>one <b>two</b></p><br>x"));
+        assert_eq!(selected(text, (16, 0), true, 't', 4), None);
+    }
+
+    #[test]
+    fn tags_at_the_start_of_the_text() {
+        assert_eq!(selected("hello", (0, 0), true, 't', 1), None);
+        assert_eq!(selected("<br>x", (4, 0), true, 't', 1), None);
+        assert_eq!(selected("<a>x</a>", (0, 0), false, 't', 1).as_deref(), Some("<a>x</a>"));
+    }
+
+    #[test]
+    fn sentences_and_paragraphs() {
+        let text = "One. Two three.  Four.\n\nNext.";
+        assert_eq!(selected(text, (7, 0), true, 's', 1).as_deref(), Some("Two three."));
+        assert_eq!(selected(text, (7, 0), false, 's', 1).as_deref(), Some("Two three.  "));
+        assert_eq!(selected(text, (7, 0), true, 'p', 1).as_deref(), Some("One. Two three.  Four.\n"));
+        assert_eq!(selected(text, (7, 0), false, 'p', 1).as_deref(), Some("One. Two three.  Four.\n\n"));
+    }
+}
@@ -0,0 +1,257 @@
+use ropey::RopeSlice;
+
+/// Kinds of characters. A word is a run of characters of the same class
+/// that isn't blank.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Blank,
+    Punctuation,
+    Keyword,
+}
+
+/// The class of `c`. Keyword characters are letters, digits and `_`, as
+/// with vim's default `iskeyword`, and combining marks so that they stay
+/// with the letter before them. With `big`, for WORDs, everything that
+/// isn't blank is one class.
+pub fn class(c: char, big: bool) -> Class {
+    if c.is_whitespace() {
+        Class::Blank
+    } else if big || c.is_alphanumeric() || c == '_' || is_mark(c) {
+        Class::Keyword
+    } else {
+        Class::Punctuation
+    }
+}
+
+fn is_mark(c: char) -> bool {
+    matches!(c, '\u{300}'..='\u{36f}' | '\u{1ab0}'..='\u{1aff}' | '\u{1dc0}'..='\u{1dff}' | '\u{20d0}'..='\u{20ff}' | '\u{fe20}'..='\u{fe2f}')
+}
+
+/// Walks the text a char at a time the way vim's word motions do, with the
+/// line break at the end of each line standing for the end of the line.
+struct Words<'a> {
+    text: RopeSlice<'a>,
+    big: bool,
+    pos: usize,
+}
+
+impl Words<'_> {
+    fn class(&self) -> Class {
+        match self.text.get_char(self.pos) {
+            Some(c) => class(c, self.big),
+            None => Class::Blank,
+        }
+    }
+
+    /// Whether the position is on a line with nothing on it, which counts
+    /// as a word.
+    fn on_empty_line(&self) -> bool {
+        let at_eol = self.text.get_char(self.pos).is_none_or(|c| c == '\n');
+        at_eol && (self.pos == 0 || self.text.char(self.pos - 1) == '\n')
+    }
+
+    fn on_last_line(&self) -> bool {
+        self.text.char_to_line(self.pos) + 1 == self.text.len_lines()
+    }
+
+    /// Moves a char on. Returns `None` at the end of the text, otherwise
+    /// whether it reached the end of a line or went on to the next one.
+    fn next(&mut self) -> Option<bool> {
+        let crossed = self.text.get_char(self.pos)? == '\n';
+        self.pos += 1;
+        Some(crossed || self.text.get_char(self.pos).is_none_or(|c| c == '\n'))
+    }
+
+    /// Moves a char back, or returns `false` at the start of the text.
+    fn prev(&mut self) -> bool {
+        if self.pos == 0 {
+            return false;
+        }
+        self.pos -= 1;
+        true
+    }
+}
+
+/// `w`: char index of the start of the `count`th word after `pos`. An
+/// empty line counts as a word. With `eol`, as for an operator, the last
+/// word stops at the end of its line rather than going on to the next
+/// one. Stays at the end of the text if there are no more words.
+pub fn forward(text: RopeSlice, pos: usize, count: usize, big: bool, eol: bool) -> usize {
+    let mut w = Words { text, big, pos };
+    for n in (0..count).rev() {
+        let stop = |moved: Option<bool>| moved.is_none_or(|eol_reached| eol_reached && eol && n == 0);
+        let start = w.class();
+        let last_line = w.on_last_line();
+        match w.next() {
+            None => break,
+            Some(true) if last_line || (eol && n == 0) => break,
+            _ => {}
+        }
+        if start != Class::Blank {
+            while w.class() == start {
+                if stop(w.next()) {
+                    return w.pos;
+                }
+            }
+        }
+        while w.class() == Class::Blank && !w.on_empty_line() {
+            if stop(w.next()) {
+                return w.pos;
+            }
+        }
+    }
+    w.pos
+}
+
+/// `b`: char index of the start of the `count`th word before `pos`.
+pub fn backward(text: RopeSlice, pos: usize, count: usize, big: bool) -> usize {
+    let mut w = Words { text, big, pos };
+    for _ in 0..count {
+        if !w.prev() {
+            break;
+        }
+        while w.class() == Class::Blank && !w.on_empty_line() {
+            if !w.prev() {
+                return w.pos;
+            }
+        }
+        if w.on_empty_line() {
+            continue;
+        }
+        let class = w.class();
+        while w.pos > 0 && class_at(text, w.pos - 1, big) == class {
+            w.pos -= 1;
+        }
+    }
+    w.pos
+}
+
+/// `e`: char index of the end of the `count`th word after `pos`. With
+/// `stay`, as for `cw`, the first step ends at the end of the word the
+/// cursor is on even if it is there already.
+pub fn end(text: RopeSlice, pos: usize, count: usize, big: bool, stay: bool) -> usize {
+    let mut w = Words { text, big, pos };
+    for n in 0..count {
+        let start = w.class();
+        let before = w.pos;
+        if w.next().is_none() {
+            return before;
+        }
+        if stay && n == 0 && start != Class::Blank && w.class() != start {
+            w.pos = before;
+            continue;
+        }
+        while w.class() == Class::Blank {
+            if w.next().is_none() {
+                return before;
+            }
+        }
+        let class = w.class();
+        while class_at(text, w.pos + 1, big) == class {
+            w.pos += 1;
+        }
+    }
+    w.pos
+}
+
+/// `ge`: char index of the end of the `count`th word before `pos`. An
+/// empty line counts as a word.
+pub fn backward_end(text: RopeSlice, pos: usize, count: usize, big: bool) -> usize {
+    let mut w = Words { text, big, pos };
+    for _ in 0..count {
+        let start = w.class();
+        if !w.prev() {
+            break;
+        }
+        if start != Class::Blank {
+            while w.class() == start {
+                if !w.prev() {
+                    return w.pos;
+                }
+            }
+        }
+        while w.class() == Class::Blank && !w.on_empty_line() {
+            if !w.prev() {
+                return w.pos;
+            }
+        }
+    }
+    w.pos
+}
+
+fn class_at(text: RopeSlice, pos: usize, big: bool) -> Class {
+    text.get_char(pos).map_or(Class::Blank, |c| class(c, big))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    const TEXT: &str = "foo.bar baz\n\n  qux-1 end";
+
+    /// Where pressing a motion again and again goes from `pos`, until it
+    /// stops moving.
+    fn steps(pos: usize, motion: impl Fn(RopeSlice, usize) -> usize) -> Vec<usize> {
+        let text = Rope::from_str(TEXT);
+        let mut stops = vec![pos];
+        loop {
+            let next = motion(text.slice(..), *stops.last().unwrap());
+            if next == *stops.last().unwrap() {
+                return stops;
+            }
+            stops.push(next);
+        }
+    }
+
+    fn once(motion: impl Fn(RopeSlice, usize) -> usize, pos: usize) -> usize {
+        motion(Rope::from_str(TEXT).slice(..), pos)
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(class('a', false), Class::Keyword);
+        assert_eq!(class('_', false), Class::Keyword);
+        assert_eq!(class('\u{301}', false), Class::Keyword);
+        assert_eq!(class('.', false), Class::Punctuation);
+        assert_eq!(class('.', true), Class::Keyword);
+        assert_eq!(class('\t', true), Class::Blank);
+    }
+
+    #[test]
+    fn words_forward() {
+        // After the last word comes the end of the text.
+        assert_eq!(steps(0, |t, p| forward(t, p, 1, false, false)), [0, 3, 4, 8, 12, 15, 18, 19, 21, 24]);
+        assert_eq!(steps(0, |t, p| forward(t, p, 1, true, false)), [0, 8, 12, 15, 21, 24]);
+        assert_eq!(once(|t, p| forward(t, p, 3, false, false), 0), 8);
+        assert_eq!(once(|t, p| forward(t, p, 2, true, false), 4), 12);
+        // For an operator the last word on a line ends with the line.
+        assert_eq!(once(|t, p| forward(t, p, 1, false, true), 8), 11);
+        assert_eq!(once(|t, p| forward(t, p, 1, false, true), 4), 8);
+    }
+
+    #[test]
+    fn words_backward() {
+        assert_eq!(steps(23, |t, p| backward(t, p, 1, false)), [23, 21, 19, 18, 15, 12, 8, 4, 3, 0]);
+        assert_eq!(steps(23, |t, p| backward(t, p, 1, true)), [23, 21, 15, 12, 8, 0]);
+        assert_eq!(once(|t, p| backward(t, p, 2, false), 23), 19);
+        assert_eq!(once(|t, p| backward(t, p, 9, true), 23), 0);
+    }
+
+    #[test]
+    fn word_ends() {
+        assert_eq!(steps(0, |t, p| end(t, p, 1, false, false)), [0, 2, 3, 6, 10, 17, 18, 19, 23]);
+        assert_eq!(steps(0, |t, p| end(t, p, 1, true, false)), [0, 6, 10, 19, 23]);
+        assert_eq!(once(|t, p| end(t, p, 2, false, false), 0), 3);
+        // `cw` on the last char of a word changes only that char.
+        assert_eq!(once(|t, p| end(t, p, 1, false, true), 2), 2);
+        assert_eq!(once(|t, p| end(t, p, 2, false, true), 2), 3);
+    }
+
+    #[test]
+    fn word_ends_backward() {
+        assert_eq!(steps(23, |t, p| backward_end(t, p, 1, false)), [23, 19, 18, 17, 12, 10, 6, 3, 2, 0]);
+        assert_eq!(steps(23, |t, p| backward_end(t, p, 1, true)), [23, 19, 12, 10, 6, 0]);
+        assert_eq!(once(|t, p| backward_end(t, p, 3, false), 23), 17);
+    }
+}
@@ -0,0 +1,40 @@
+use ropey::RopeSlice;
+
+use crate::grapheme;
+
+/// The part of a line shown on one screen row when `wrap` is set.
+#[derive(Debug, Clone, Copy)]
+pub struct Row {
+    /// Chars of the line on this row.
+    pub start: usize,
+    pub end: usize,
+    /// Screen column of `start` when the line is laid out unwrapped, which
+    /// is what tabs are measured against.
+    pub vcol: usize,
+}
+
+/// Splits a line into rows of `width` screen columns. Rows after the first
+/// lose `indent` columns to `showbreak`. A grapheme that doesn't fit at the
+/// end of a row moves to the next one whole. Every line has at least one
+/// row.
+pub fn rows(line: RopeSlice, width: usize, indent: usize, tabstop: usize) -> Vec<Row> {
+    let mut rows = Vec::new();
+    let mut row = Row { start: 0, end: 0, vcol: 0 };
+    let (mut col, mut used, mut pos) = (0, 0, 0);
+    for g in grapheme::graphemes(line) {
+        let w = grapheme::width(g, col, tabstop);
+        let room = if rows.is_empty() { width } else { width.saturating_sub(indent) }.max(1);
+        if used > 0 && used + w > room {
+            row.end = pos;
+            rows.push(row);
+            row = Row { start: pos, end: pos, vcol: col };
+            used = 0;
+        }
+        used += w;
+        col += w;
+        pos += g.len_chars();
+    }
+    row.end = pos;
+    rows.push(row);
+    rows
+}